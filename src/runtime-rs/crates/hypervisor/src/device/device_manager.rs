@@ -8,6 +8,7 @@ use std::{
     collections::HashMap,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context, Result};
@@ -681,22 +682,42 @@ pub async fn do_handle_device(
     d: &RwLock<DeviceManager>,
     dev_info: &DeviceConfig,
 ) -> Result<DeviceType> {
-    let device_id = d
-        .write()
+    handle_device_locked(&mut *d.write().await, dev_info).await
+}
+
+// Same as do_handle_device, giving up with a "device manager busy" error if
+// the device manager lock is not acquired within the timeout.
+pub async fn do_handle_device_within(
+    d: &RwLock<DeviceManager>,
+    dev_info: &DeviceConfig,
+    timeout: Duration,
+) -> Result<DeviceType> {
+    let mut dm = tokio::time::timeout(timeout, d.write())
         .await
+        .map_err(|_| {
+            anyhow!(
+                "device manager busy: lock not acquired within {:?}",
+                timeout
+            )
+        })?;
+
+    handle_device_locked(&mut dm, dev_info).await
+}
+
+async fn handle_device_locked(
+    dm: &mut DeviceManager,
+    dev_info: &DeviceConfig,
+) -> Result<DeviceType> {
+    let device_id = dm
         .new_device(dev_info)
         .await
         .context("failed to create device")?;
 
-    d.write()
-        .await
-        .try_add_device(&device_id)
+    dm.try_add_device(&device_id)
         .await
         .context("failed to add device")?;
 
-    let device_info = d
-        .read()
-        .await
+    let device_info = dm
         .get_device_info(&device_id)
         .await
         .context("failed to get device info")?;
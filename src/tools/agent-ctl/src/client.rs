@@ -8,8 +8,10 @@
 use crate::types::*;
 use crate::utils;
 use crate::vm;
+use crate::vm::error::VmError;
 use anyhow::{anyhow, Result};
 use byteorder::ByteOrder;
+use kata_types::device::DRIVER_BLK_PCI_TYPE;
 use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, UnixAddr, VsockAddr};
 use protocols::agent::*;
 use protocols::agent_ttrpc::*;
@@ -23,8 +25,9 @@ use std::io::{self, Read, Seek, SeekFrom};
 use std::io::{BufRead, BufReader};
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use ttrpc::context::Context;
 
 // Run the specified closure to set an automatic value if the ttRPC Context
@@ -101,6 +104,15 @@ const DEFAULT_PROC_SIGNAL: &str = "SIGKILL";
 
 const ERR_API_FAILED: &str = "API failed";
 
+const ERR_INTERRUPTED: &str = "interrupted";
+
+// Interval at which waiting for interactive input checks for cancellation
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Container paths of the name resolution files provided by the host
+const GUEST_RESOLV_CONF: &str = "/etc/resolv.conf";
+const GUEST_HOSTS_FILE: &str = "/etc/hosts";
+
 // Value used as a "namespace" in the ttRPC Context's metadata.
 const METADATA_CFG_NS: &str = "agent-ctl-cfg";
 
@@ -114,6 +126,78 @@ const AUTO_VALUES_CFG_NAME: &str = "auto-values";
 const RETRY_AGENT_CONNECT: u64 = 300;
 const DIAL_TIMEOUT: u64 = 10;
 
+// Agent socket schemes returned by the hypervisors, mapped to the client
+// scheme and whether the transport is hybrid vsock:
+// - cloud-hypervisor: "hvsock://<path>", a hybrid vsock unix socket
+// - qemu: "vsock://<cid>", a regular vsock
+const AGENT_SOCKET_SCHEMES: &[(&str, &str, bool)] =
+    &[("hvsock", "unix", true), ("vsock", "vsock", false)];
+
+// Per attempt timeout and interval between agent readiness checks, the
+// interval doubling up to the maximum one
+const AGENT_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const AGENT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const AGENT_CHECK_MAX_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time for the agent of a booted test vm to become ready, unless a boot
+// timeout is given
+const AGENT_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Environment and stdout read size used for commands run in the guest
+// on behalf of the tool itself.
+const GUEST_EXEC_PATH_ENV: &str =
+    "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const GUEST_EXEC_READ_LEN: u32 = 4096;
+
+// Default amount of data read to measure a storage throughput, and the
+// file used for it below the storage mount point
+const DEFAULT_THROUGHPUT_SIZE_MB: u32 = 100;
+const THROUGHPUT_TEST_FILE: &str = ".agent-ctl-throughput";
+
+// Name and value size of the xattr set to check the passthrough
+const XATTR_TEST_NAME: &str = "user.agent-ctl.test";
+const XATTR_TEST_VALUE_LEN: u32 = 16;
+
+// File a container attempts to create to check a storage is read-only, and
+// the error expected from the guest kernel
+const READ_ONLY_TEST_FILE: &str = ".agent-ctl-read-only";
+const READ_ONLY_ERROR: &str = "Read-only file system";
+
+// File written by a container and read back by another one to check they
+// share a storage, with the size of its random content
+const SHARED_STORAGE_TEST_FILE: &str = ".agent-ctl-shared";
+const SHARED_STORAGE_TOKEN_LEN: u32 = 16;
+
+// Default time to wait for a started container to create its ready marker,
+// and the interval between checks for the marker
+const DEFAULT_READY_TIMEOUT: &str = "30s";
+const READY_MARKER_INTERVAL: Duration = Duration::from_millis(100);
+
+// Guest sysfs directory of the PCI root bus, where PCI paths start
+const GUEST_PCI_ROOT_PATH: &str = "/sys/devices/pci0000:00";
+
+// Commands run in the guest to collect its logs
+const GUEST_LOG_CMDS: &[&[&str]] = &[&["dmesg"], &["cat", "/proc/self/mountinfo"]];
+
+// Guest kernel messages matching one of these (case insensitive) are added
+// to the error of a failed storage request, keeping only the last ones
+const STORAGE_DMESG_KEYWORDS: &[&str] = &[
+    "ext4-fs",
+    "xfs",
+    "btrfs",
+    "isofs",
+    "squashfs",
+    "virtiofs",
+    "virtio_blk",
+    "fuse",
+    "scsi",
+    "loop",
+    "i/o error",
+    "superblock",
+    "mount",
+];
+const STORAGE_DMESG_MAX_LINES: usize = 20;
+
 static AGENT_CMDS: &[AgentCmd] = &[
     AgentCmd {
         name: "AddARPNeighbors",
@@ -180,6 +264,11 @@ static AGENT_CMDS: &[AgentCmd] = &[
         st: ServiceType::Agent,
         fp: agent_cmd_sandbox_get_metrics,
     },
+    AgentCmd {
+        name: "GetMountPropagation",
+        st: ServiceType::Agent,
+        fp: agent_cmd_container_get_mount_propagation,
+    },
     AgentCmd {
         name: "GetOOMEvent",
         st: ServiceType::Agent,
@@ -200,6 +289,11 @@ static AGENT_CMDS: &[AgentCmd] = &[
         st: ServiceType::Agent,
         fp: agent_cmd_sandbox_list_routes,
     },
+    AgentCmd {
+        name: "MeasureReadThroughput",
+        st: ServiceType::Agent,
+        fp: agent_cmd_container_measure_read_throughput,
+    },
     AgentCmd {
         name: "MemHotplugByProbe",
         st: ServiceType::Agent,
@@ -285,6 +379,31 @@ static AGENT_CMDS: &[AgentCmd] = &[
         st: ServiceType::Agent,
         fp: agent_cmd_sandbox_update_routes,
     },
+    AgentCmd {
+        name: "VerifyPciPath",
+        st: ServiceType::Agent,
+        fp: agent_cmd_container_verify_pci_path,
+    },
+    AgentCmd {
+        name: "VerifyReadOnly",
+        st: ServiceType::Agent,
+        fp: agent_cmd_container_verify_read_only,
+    },
+    AgentCmd {
+        name: "VerifySharedStorage",
+        st: ServiceType::Agent,
+        fp: agent_cmd_container_verify_shared_storage,
+    },
+    AgentCmd {
+        name: "VerifyXattr",
+        st: ServiceType::Agent,
+        fp: agent_cmd_container_verify_xattr,
+    },
+    AgentCmd {
+        name: "WaitContainerReady",
+        st: ServiceType::Agent,
+        fp: agent_cmd_container_wait_ready,
+    },
     AgentCmd {
         name: "WaitProcess",
         st: ServiceType::Agent,
@@ -312,6 +431,11 @@ static AGENT_CMDS: &[AgentCmd] = &[
     },
 ];
 
+lazy_static! {
+    // Test vm booted for the commands, which the vm built-in commands act on
+    static ref TEST_VM: Mutex<Option<vm::TestVm>> = Mutex::new(None);
+}
+
 static BUILTIN_CMDS: & [BuiltinCmd] = &[
     BuiltinCmd {
         name: "echo",
@@ -333,6 +457,11 @@ static BUILTIN_CMDS: & [BuiltinCmd] = &[
         descr: "Repeat the next command 'n' times [-1 for forever]",
         fp: builtin_cmd_repeat,
     },
+    BuiltinCmd {
+        name: "resize",
+        descr: "Resize the test VM to vcpus=N and/or memory=SIZE_MB, up to the configured maximums",
+        fp: builtin_cmd_resize,
+    },
     BuiltinCmd {
         name: "sleep",
         descr:
@@ -645,28 +774,60 @@ pub fn client(cfg: &mut Config, commands: Vec<&str>) -> Result<()> {
         return Ok(());
     }
 
+    if cfg.dump_config {
+        let config = vm::dump_effective_config(&cfg.hypervisor_name, &test_vm_config(cfg))?;
+        print!("{config}");
+        return Ok(());
+    }
+
     announce(cfg);
 
+    // Tear down the test vm in order on Ctrl-C or SIGTERM
+    if !cfg.hypervisor_name.is_empty() {
+        vm::cancel_on_signals()?;
+    }
+
+    if cfg.prepare_only {
+        return prepare_vm(cfg);
+    }
+
     let vm_ref = handle_vm(cfg)?;
+    *TEST_VM.lock().unwrap() = vm_ref.clone();
+
+    // persist the vm state so it can be torn down if this process dies
+    if let Some(vm_ref) = &vm_ref {
+        if !cfg.state_file.is_empty() {
+            if let Err(e) = vm::save_vm_state(vm_ref, &cfg.state_file) {
+                warn!(sl!(), "failed to save vm state: {:?}", e);
+            }
+        }
+    }
 
     info!(sl!(), "run commands");
-    let result = run_commands(cfg, commands);
+    let result = if cfg.agent_version.is_empty() {
+        run_commands(cfg, commands)
+    } else {
+        assert_agent_version(cfg, &cfg.agent_version).and_then(|_| run_commands(cfg, commands))
+    };
 
     // stop the vm if booted
+    TEST_VM.lock().unwrap().take();
     if let Some(vm_ref) = vm_ref {
         info!(sl!(), "stopping test vm");
-        // TODO: The error handling here is for cloud-hypervisor.
-        // We use tokio::runtime to call the async operations of
-        // runtime-rs::crates::hypervisor::_vm
-        // These methods can spawn some additional functions, ex
-        // `clh::inner_hypervisor::cloud_hypervisor_log_output`
-        // But since we return from the tokio::runtime block
-        // the runtime is dropped. During stop_vm call, cloud hypervisor
-        // waits for the logger task which is in cancelled state as a result.
         match vm::remove_vm(vm_ref) {
-            Ok(_) => info!(sl!(), "Successfully shut down test vm"),
+            Ok(_) => {
+                info!(sl!(), "Successfully shut down test vm");
+
+                if !cfg.state_file.is_empty() {
+                    let _ = fs::remove_file(&cfg.state_file);
+                }
+            }
             Err(e) => warn!(sl!(), "Error shutting down vm:{:?}", e),
         }
+
+        if cfg.cleanup_temp {
+            vm::vm_utils::cleanup_temp_paths();
+        }
     }
 
     result.map_err(|e| anyhow!(e))
@@ -681,37 +842,324 @@ fn handle_vm(cfg: &mut Config) -> Result<Option<vm::TestVm>> {
     }
 
     // Boot the test vm
-    let vm_instance = vm::setup_vm(&cfg.hypervisor_name)?;
+    let vm_instance = boot_test_vm(&cfg.hypervisor_name, &test_vm_config(cfg))?;
     info!(
         sl!(),
         "booted test vm with hypervisor: {:?}", vm_instance.hypervisor_name
     );
 
-    // set the vsock server address for connecting with ttrpc server
-    if !vm_instance.socket_addr.is_empty() {
-        match vm_instance.hybrid_vsock {
-            true => {
-                // hybrid vsock URI expects unix prefix
-                let addr_fields: Vec<&str> = vm_instance.socket_addr.split("://").collect();
-                cfg.server_address = format!("{}://{}", "unix", addr_fields[1]);
-                cfg.hybrid_vsock = true;
+    // without an agent to talk to, leave the vm running for inspection,
+    // provided its state can be saved for the teardown
+    if !vm_instance.agent_reachable() {
+        match vm::save_vm_state(&vm_instance, &cfg.state_file) {
+            Ok(_) => {
+                // released by the teardown of the saved state instead
+                vm::vm_utils::untrack_loop_devices(&vm_instance.scratch_disks);
+                warn!(sl!(), "test vm left running without an agent socket";
+                    "state_file" => &cfg.state_file);
             }
-            false => {
-                let addr = vm_instance.socket_addr.clone();
-                cfg.server_address = format!("{}:{}", addr, 1024);
-                cfg.hybrid_vsock = false;
+            Err(e) => {
+                warn!(sl!(), "failed to save vm state: {:?}", e);
+                if let Err(stop_err) = vm::remove_vm(vm_instance) {
+                    warn!(sl!(), "Error shutting down vm:{:?}", stop_err);
+                }
             }
         }
+        return Err(anyhow!("test vm booted without an agent socket"));
+    }
+
+    // the vm is running, so stop it rather than leak it on failure
+    if let Err(e) = use_vm(cfg, &vm_instance) {
+        if let Err(stop_err) = vm::remove_vm(vm_instance) {
+            warn!(sl!(), "Error shutting down vm:{:?}", stop_err);
+        }
+        return Err(e);
+    }
+
+    Ok(Some(vm_instance))
+}
+
+// Boot the test vm, telling a setup which cannot work, to be fixed before
+// running again, from a boot which failed along the way
+fn boot_test_vm(hypervisor_name: &str, vm_config: &vm::TestVmConfig) -> Result<vm::TestVm> {
+    match vm::setup_vm(hypervisor_name, vm_config) {
+        Ok(vm_instance) => Ok(vm_instance),
+        Err(e @ (VmError::UnsupportedHypervisor(_) | VmError::ConfigLoad(_))) => {
+            Err(anyhow!(e).context("invalid test vm setup"))
+        }
+        Err(VmError::Cancelled) => Err(anyhow!(ERR_INTERRUPTED)),
+        Err(e) => {
+            warn!(sl!(), "test vm boot failed";
+                "step" => e.to_string(),
+                "hypervisor" => hypervisor_name);
+            Err(anyhow!(e))
+        }
+    }
+}
+
+// Run the test vm boot up to starting it, to validate its configuration,
+// and tear it back down
+fn prepare_vm(cfg: &Config) -> Result<()> {
+    let result = vm::check_vm_boot(&cfg.hypervisor_name, &test_vm_config(cfg));
+
+    if cfg.cleanup_temp {
+        vm::vm_utils::cleanup_temp_paths();
+    }
+
+    result?;
+
+    info!(sl!(), "test vm boot configuration is valid";
+        "hypervisor" => &cfg.hypervisor_name);
+
+    Ok(())
+}
+
+// Per run test vm settings
+fn test_vm_config(cfg: &Config) -> vm::TestVmConfig {
+    vm::TestVmConfig {
+        config_path: Some(cfg.hypervisor_config_path.clone()).filter(|p| !p.is_empty()),
+        io_threads: cfg.io_threads,
+        rootfs_dir: cfg.rootfs_dir.clone(),
+        scratch_disks: cfg.scratch_disks.clone(),
+        attach_jobs: cfg.attach_jobs,
+        mem_prealloc: cfg.mem_prealloc,
+        hugepages: cfg.hugepages,
+        mem_shared: cfg.mem_shared,
+        iso_images: cfg.iso_images.clone(),
+        pmem_image: cfg.pmem_image.clone(),
+        tap_device: cfg.tap_device.clone(),
+        vfio_devices: cfg.vfio_devices.clone(),
+        virtiofs_shares: cfg.virtiofs_shares.clone(),
+        virtio_fs_cache: cfg.virtio_fs_cache.clone(),
+        virtio_fs_dax_window: cfg.virtio_fs_dax_window,
+        boot_timeout: cfg.boot_timeout,
+        stop_timeout: cfg.stop_timeout,
+        stop_kill: cfg.stop_kill,
+        keep_without_agent: cfg.keep_without_agent,
+    }
+}
+
+// Point the config at the booted test vm
+fn use_vm(cfg: &mut Config, vm_instance: &vm::TestVm) -> Result<()> {
+    let timings = &vm_instance.boot_timings;
+    utils::record_probe_result(ProbeResult::BootTimings {
+        hypervisor: vm_instance.hypervisor_name.clone(),
+        config_load_ms: timings.config_load.as_millis() as u64,
+        prepare_ms: timings.prepare.as_millis() as u64,
+        devices_ms: timings
+            .devices
+            .iter()
+            .map(|(group, time)| (group.clone(), time.as_millis() as u64))
+            .collect(),
+        share_fs_ms: timings.share_fs.as_millis() as u64,
+        start_ms: timings.start.as_millis() as u64,
+    });
+
+    // report the device models and features in effect, which may differ
+    // from the requested ones
+    match vm::negotiated_features(vm_instance) {
+        Ok(features) => {
+            info!(sl!(), "test vm negotiated features";
+                "features" => format!("{:?}", features));
+
+            utils::record_probe_result(ProbeResult::NegotiatedFeatures {
+                hypervisor: vm_instance.hypervisor_name.clone(),
+                block_driver: features.block_driver,
+                rootfs_driver: features.rootfs_driver,
+                shared_fs: features.shared_fs,
+                vsock: features.vsock,
+                io_threads: features.io_threads,
+            });
+        }
+        Err(e) => warn!(sl!(), "failed to query test vm features: {:?}", e),
+    }
+
+    if cfg.print_cmdline {
+        println!("kernel command line: {}", vm::kernel_cmdline(vm_instance)?);
+    }
+
+    for device in &vm_instance.vfio_devices {
+        info!(sl!(), "vfio device passed through";
+            "bdf" => &device.bdf,
+            "guest_pci_path" => &device.guest_pci_path);
+    }
+
+    // set the vsock server address for connecting with ttrpc server
+    let (server_address, hybrid_vsock) =
+        agent_server_address(&vm_instance.socket_addr, vm_instance.agent_port)?;
+    cfg.server_address = server_address;
+    cfg.hybrid_vsock = hybrid_vsock;
+    if hybrid_vsock {
+        cfg.hybrid_vsock_port = vm_instance.agent_port as u64;
     }
 
     // set the fs share path in config
-    if vm_instance.share_fs.pid != 0 {
+    if !vm_instance.share_fs.shared_path.is_empty() {
         debug!(sl!(), "share path: {}", cfg.shared_fs_host_path);
         cfg.shared_fs_host_path = vm_instance.share_fs.shared_path.clone();
+        cfg.shared_fs_backend = vm_instance.share_fs.backend.clone();
+
+        if !vm_instance.share_fs.log_path.is_empty() {
+            info!(
+                sl!(),
+                "virtiofsd output in {}", vm_instance.share_fs.log_path
+            );
+        }
     }
 
+    // the attached disks and extra shares get mounted in the guest along
+    // with the sandbox
+    cfg.disk_storages.extend(
+        vm_instance
+            .scratch_disks
+            .iter()
+            .map(|d| d.storage.clone())
+            .chain(vm_instance.iso_storages.iter().cloned())
+            .chain(vm_instance.pmem_storage.iter().cloned())
+            .chain(vm_instance.extra_share_storages.iter().cloned()),
+    );
+
     info!(sl!(), "socket server addr: {}", cfg.server_address);
-    Ok(Some(vm_instance))
+
+    // the vm being started does not mean the agent is listening yet
+    wait_for_agent(vm_instance, cfg.boot_timeout.unwrap_or(AGENT_READY_TIMEOUT))?;
+
+    if let Some(window) = cfg.pause_window {
+        info!(sl!(), "pausing test vm"; "window" => format!("{:?}", window));
+
+        vm::pause_test_vm(vm_instance)?;
+        sleep(window);
+        vm::resume_test_vm(vm_instance)?;
+    }
+
+    // the hotplugged resources are left for the commands to online
+    resize_test_vm(vm_instance, cfg.resize_vcpus, cfg.resize_memory_mb)
+}
+
+// Resize the vcpus and memory of the test vm to the requested amounts, for
+// the resize flags and the "resize" built-in command
+fn resize_test_vm(
+    vm_instance: &vm::TestVm,
+    vcpus: Option<u32>,
+    memory_mb: Option<u32>,
+) -> Result<()> {
+    if let Some(count) = vcpus {
+        let vcpus = vm::resize_vcpus(vm_instance, count)?;
+        info!(sl!(), "resized test vm vcpus"; "requested" => count, "vcpus" => vcpus);
+    }
+
+    if let Some(size_mb) = memory_mb {
+        let memory_mb = vm::resize_memory(vm_instance, size_mb)?;
+        info!(sl!(), "resized test vm memory"; "requested_mb" => size_mb, "memory_mb" => memory_mb);
+    }
+
+    Ok(())
+}
+
+// Convert the agent socket URI returned by the hypervisor into a server
+// address understood by the ttrpc client, the agent port being appended to
+// regular vsock ones. Returns the address along with whether it is a hybrid
+// vsock one.
+fn agent_server_address(socket_addr: &str, agent_port: u32) -> Result<(String, bool)> {
+    let (scheme, addr) = socket_addr
+        .split_once("://")
+        .ok_or_else(|| anyhow!("invalid agent socket address: {:?}", socket_addr))?;
+
+    let (_, client_scheme, is_hybrid_vsock) = AGENT_SOCKET_SCHEMES
+        .iter()
+        .find(|(transport, _, _)| *transport == scheme)
+        .ok_or_else(|| anyhow!("unsupported agent socket scheme: {:?}", scheme))?;
+
+    // regular vsock addresses only carry the guest cid
+    let server_address = if *is_hybrid_vsock || addr.contains(':') {
+        format!("{client_scheme}://{addr}")
+    } else {
+        format!("{client_scheme}://{addr}:{agent_port}")
+    };
+
+    Ok((server_address, *is_hybrid_vsock))
+}
+
+// Wait until the agent of the booted test vm answers a health check, or
+// the timeout expires.
+fn wait_for_agent(vm_instance: &vm::TestVm, timeout: Duration) -> Result<()> {
+    let (server_address, hybrid_vsock) =
+        agent_server_address(&vm_instance.socket_addr, vm_instance.agent_port)?;
+    let deadline = Instant::now() + timeout;
+    let mut interval = AGENT_CHECK_INTERVAL;
+
+    loop {
+        let result = kata_service_health(
+            server_address.clone(),
+            vm_instance.agent_port as u64,
+            hybrid_vsock,
+        )
+        .and_then(|health| {
+            let ctx = ttrpc::context::with_timeout(AGENT_CHECK_TIMEOUT.as_nanos() as i64);
+            health
+                .check(ctx, &CheckRequest::default())
+                .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))
+        });
+
+        match result {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(e.context(format!("agent not ready after {timeout:?}")));
+            }
+            Err(e) => debug!(sl!(), "agent not ready yet: {:?}", e),
+        }
+
+        sleep(interval.min(deadline.saturating_duration_since(Instant::now())));
+        interval = (interval * 2).min(AGENT_CHECK_MAX_INTERVAL);
+    }
+}
+
+// Query the agent version and check it against the expected one, which is
+// either an exact version or a list of comparisons (ex: ">=3.2.0, <4.0.0").
+fn assert_agent_version(cfg: &Config, expected: &str) -> Result<()> {
+    let health = kata_service_health(
+        cfg.server_address.clone(),
+        cfg.hybrid_vsock_port,
+        cfg.hybrid_vsock,
+    )?;
+
+    let ctx = ttrpc::context::with_timeout(cfg.timeout_nano);
+
+    let reply = health
+        .version(ctx, &CheckRequest::default())
+        .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
+
+    let actual = reply.agent_version();
+
+    if !utils::version_matches(actual, expected)? {
+        return Err(anyhow!(
+            "agent version mismatch: expected {:?}, actual {:?}",
+            expected,
+            actual
+        ));
+    }
+
+    info!(sl!(), "agent version {} matches {:?}", actual, expected);
+
+    Ok(())
+}
+
+// Boot a test vm and measure the wall-clock time until its agent answers
+// the first request. The vm is torn down before returning.
+pub fn measure_boot(hypervisor_name: &str, timeout: Duration) -> Result<Duration> {
+    let start = Instant::now();
+
+    let vm_instance = boot_test_vm(hypervisor_name, &vm::TestVmConfig::default())?;
+
+    let result = wait_for_agent(&vm_instance, timeout).map(|_| start.elapsed());
+
+    if let Err(e) = vm::remove_vm(vm_instance) {
+        warn!(sl!(), "Error shutting down vm:{:?}", e);
+    }
+    vm::vm_utils::detach_loop_devices();
+    vm::vm_utils::cleanup_temp_paths();
+
+    result
 }
 
 fn run_commands(cfg: &Config, commands: Vec<&str>) -> Result<()> {
@@ -752,32 +1200,73 @@ fn run_commands(cfg: &Config, commands: Vec<&str>) -> Result<()> {
     // when running with `--vm`, add host path for fs share to options
     if !cfg.shared_fs_host_path.is_empty() {
         options.insert("shared-path".to_string(), cfg.shared_fs_host_path.clone());
+        options.insert("shared-fs".to_string(), cfg.shared_fs_backend.clone());
+    }
+
+    // cgroup limits applied to the created containers
+    if cfg.memory_limit > 0 {
+        options.insert("memory-limit".to_string(), cfg.memory_limit.to_string());
+    }
+
+    if cfg.cpu_quota > 0 {
+        options.insert("cpu-quota".to_string(), cfg.cpu_quota.to_string());
+    }
+
+    // host files provided to the guest containers
+    if !cfg.resolv_conf.is_empty() {
+        options.insert("resolv-conf".to_string(), cfg.resolv_conf.clone());
+    }
+
+    if !cfg.hosts_file.is_empty() {
+        options.insert("hosts-file".to_string(), cfg.hosts_file.clone());
+    }
+
+    if !cfg.disk_storages.is_empty() {
+        let storages = serde_json::to_string(&cfg.disk_storages)?;
+        options.insert("disk-storages".to_string(), storages);
     }
 
     info!(sl!(), "client setup complete";
         "server-address" => cfg.server_address.to_string());
 
-    if cfg.interactive {
-        return interactive_client_loop(cfg, &mut options, &client, &health, &ttrpc_ctx);
+    let result = if cfg.interactive {
+        interactive_client_loop(cfg, &mut options, &client, &health, &ttrpc_ctx)
+    } else {
+        batch_client_loop(cfg, &mut options, &client, &health, &ttrpc_ctx, commands)
+    };
+
+    // Collect the guest logs before the vm gets torn down, even (and
+    // especially) if a command failed.
+    if !cfg.guest_logs_file.is_empty() {
+        if let Err(e) = collect_guest_logs(&ttrpc_ctx, &client, &options, &cfg.guest_logs_file) {
+            warn!(sl!(), "failed to collect guest logs: {:?}", e);
+        }
     }
 
+    result
+}
+
+fn batch_client_loop(
+    cfg: &Config,
+    options: &mut Options,
+    client: &AgentServiceClient,
+    health: &HealthClient,
+    ctx: &Context,
+    commands: Vec<&str>,
+) -> Result<()> {
     let mut repeat_count = 1;
 
     for cmd in commands {
+        if vm::is_cancelled() {
+            return Err(anyhow!(ERR_INTERRUPTED));
+        }
+
         if cmd.starts_with(CMD_REPEAT) {
             repeat_count = get_repeat_count(cmd);
             continue;
         }
 
-        let (result, shutdown) = handle_cmd(
-            cfg,
-            &client,
-            &health,
-            &ttrpc_ctx,
-            repeat_count,
-            &mut options,
-            cmd,
-        );
+        let (result, shutdown) = handle_cmd(cfg, client, health, ctx, repeat_count, options, cmd);
 
         result.map_err(|e| anyhow!(e))?;
 
@@ -792,6 +1281,38 @@ fn run_commands(cfg: &Config, commands: Vec<&str>) -> Result<()> {
     Ok(())
 }
 
+// Write the guest kernel log and the mounts seen from the last container
+// used to the host file. The agent log is not reachable from inside a
+// container, it can be found in the hypervisor console output instead.
+fn collect_guest_logs(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    options: &Options,
+    path: &str,
+) -> Result<()> {
+    let cid = options
+        .get("cid")
+        .ok_or_else(|| anyhow!("no container to collect the guest logs from"))?;
+
+    let mut logs = String::new();
+
+    for cmd in GUEST_LOG_CMDS {
+        let output = match guest_exec(ctx, client, cid, cmd) {
+            Ok((0, output)) => output,
+            Ok((status, output)) => format!("{output}(exit status {status})\n"),
+            Err(e) => format!("{e:?}\n"),
+        };
+
+        logs.push_str(&format!("==> {} <==\n{}\n", cmd.join(" "), output));
+    }
+
+    fs::write(path, logs).map_err(|e| anyhow!("failed to write guest logs to {}: {}", path, e))?;
+
+    info!(sl!(), "guest logs written to {}", path);
+
+    Ok(())
+}
+
 // Handle internal and agent API commands.
 fn handle_cmd(
     cfg: &Config,
@@ -926,8 +1447,23 @@ fn interactive_client_loop(
     let mut repeat_count: i64 = 1;
 
     loop {
-        let cmdline =
-            readline("Enter command").map_err(|e| anyhow!(e).context("failed to read line"))?;
+        let cmdline = match readline("Enter command", cfg.idle_timeout)
+            .map_err(|e| anyhow!(e).context("failed to read line"))?
+        {
+            Some(cmdline) => cmdline,
+            None if vm::is_cancelled() => return Err(anyhow!(ERR_INTERRUPTED)),
+            None => {
+                warn!(
+                    sl!(),
+                    "no command received for {:?}, shutting down", cfg.idle_timeout
+                );
+                break;
+            }
+        };
+
+        if vm::is_cancelled() {
+            return Err(anyhow!(ERR_INTERRUPTED));
+        }
 
         if cmdline.is_empty() {
             continue;
@@ -954,13 +1490,23 @@ fn interactive_client_loop(
     Ok(())
 }
 
-fn readline(prompt: &str) -> std::result::Result<String, String> {
+// Read a line from stdin. None is returned when no input arrived within the
+// idle timeout, if specified, or once the test vm operations got cancelled.
+fn readline(
+    prompt: &str,
+    idle_timeout: Option<Duration>,
+) -> std::result::Result<Option<String>, String> {
     print!("{prompt}: ");
 
     io::stdout()
         .flush()
         .map_err(|e| format!("failed to flush: {e:?}"))?;
 
+    if !wait_for_input(idle_timeout)? {
+        println!();
+        return Ok(None);
+    }
+
     let mut line = String::new();
 
     std::io::stdin()
@@ -968,7 +1514,55 @@ fn readline(prompt: &str) -> std::result::Result<String, String> {
         .map_err(|e| format!("failed to read line: {e:?}"))?;
 
     // Remove NL
-    Ok(line.trim_end().to_string())
+    Ok(Some(line.trim_end().to_string()))
+}
+
+// Wait for stdin to become readable, returning false if the idle timeout
+// expired or the test vm operations got cancelled first. The wait is done
+// in short polls since the signals cancelling the operations are not
+// necessarily delivered to this thread, and a blocking read would be
+// restarted anyway.
+fn wait_for_input(idle_timeout: Option<Duration>) -> std::result::Result<bool, String> {
+    let deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
+
+    loop {
+        if vm::is_cancelled() {
+            return Ok(false);
+        }
+
+        let interval = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(false);
+                }
+                left.min(INPUT_POLL_INTERVAL)
+            }
+            None => INPUT_POLL_INTERVAL,
+        };
+
+        let mut fds = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let timeout_ms = i32::try_from(interval.as_millis()).unwrap_or(i32::MAX);
+
+        // SAFETY: fds is a single valid pollfd living for the whole call
+        let ret = unsafe { libc::poll(&mut fds, 1, timeout_ms) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(format!("failed to wait for input: {err:?}"));
+        }
+
+        if ret > 0 {
+            return Ok(true);
+        }
+    }
 }
 
 fn agent_cmd_health_check(
@@ -982,14 +1576,13 @@ fn agent_cmd_health_check(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = health
         .check(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1006,14 +1599,13 @@ fn agent_cmd_health_version(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = health
         .version(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1022,7 +1614,7 @@ fn agent_cmd_sandbox_create(
     ctx: &Context,
     client: &AgentServiceClient,
     _health: &HealthClient,
-    _options: &mut Options,
+    options: &mut Options,
     args: &str,
 ) -> Result<()> {
     let mut req: CreateSandboxRequest = utils::make_request(args)?;
@@ -1032,16 +1624,27 @@ fn agent_cmd_sandbox_create(
         req.set_sandbox_id(utils::random_sandbox_id());
     }
 
-    let ctx = clone_context(ctx);
+    // Mount the disks attached to the test vm and the tmpfs storages
+    if let Some(storages) = options.get("disk-storages") {
+        let storages: Vec<Storage> = serde_json::from_str(storages)
+            .map_err(|e| anyhow!("invalid disk storages: {:?}", e))?;
+        req.mut_storages().extend(storages);
+    }
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
-        .create_sandbox(ctx, &req)
-        .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
+        .create_sandbox(clone_context(ctx), &req)
+        .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))
+        .map_err(|e| {
+            if req.storages.is_empty() {
+                e
+            } else {
+                with_storage_diagnostics(ctx, client, options, e)
+            }
+        })?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1057,14 +1660,13 @@ fn agent_cmd_sandbox_destroy(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .destroy_sandbox(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1083,28 +1685,71 @@ fn agent_cmd_container_create(
         return Err(anyhow!("CreateContainer needs image reference"));
     }
 
-    let ctx = clone_context(ctx);
-
     // setup host/guest fs sharing for container rootfs
     let share_fs_path = match options.get("shared-path") {
         Some(p) => p.to_string(),
         None => "".to_string(),
     };
 
-    let req = utils::make_create_container_request(input, share_fs_path)?;
+    let shared_fs = match options.get("shared-fs") {
+        Some(fs) => fs.to_string(),
+        None => "".to_string(),
+    };
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    let mut req = utils::make_create_container_request(input, share_fs_path.clone(), &shared_fs)?;
+
+    let memory_limit = get_limit_option(options, "memory-limit")?;
+    let cpu_quota = get_limit_option(options, "cpu-quota")?;
+    utils::set_resource_limits(req.mut_OCI(), memory_limit, cpu_quota);
+
+    // share the name resolution files through the fs share
+    let etc_files: Vec<(&str, &str)> = [
+        (GUEST_RESOLV_CONF, options.get("resolv-conf")),
+        (GUEST_HOSTS_FILE, options.get("hosts-file")),
+    ]
+    .into_iter()
+    .filter_map(|(dest, src)| src.map(|s| (dest, s.as_str())))
+    .collect();
+
+    if !etc_files.is_empty() {
+        if share_fs_path.is_empty() {
+            return Err(anyhow!(
+                "sharing files with the container requires a test vm"
+            ));
+        }
+
+        let c_id = req.container_id().to_string();
+        utils::share_etc_files(req.mut_OCI(), &share_fs_path, &c_id, &etc_files)?;
+    }
+
+    utils::log_request(&req);
 
     let reply = client
-        .create_container(ctx, &req)
-        .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
+        .create_container(clone_context(ctx), &req)
+        .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))
+        .map_err(|e| {
+            if req.storages.is_empty() {
+                e
+            } else {
+                with_storage_diagnostics(ctx, client, options, e)
+            }
+        })?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
 
+// Returns the value of a resource limit option, or zero if it is not set.
+fn get_limit_option(options: &Options, name: &str) -> Result<i64> {
+    match options.get(name) {
+        Some(v) => v
+            .parse::<i64>()
+            .map_err(|e| anyhow!("invalid {} option {:?}: {:?}", name, v, e)),
+        None => Ok(0),
+    }
+}
+
 fn agent_cmd_container_remove(
     ctx: &Context,
     client: &AgentServiceClient,
@@ -1116,14 +1761,13 @@ fn agent_cmd_container_remove(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .remove_container(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     // Unmount the share fs
     let share_fs_path = match options.get("shared-path") {
@@ -1172,14 +1816,13 @@ fn agent_cmd_container_exec(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .exec_process(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1202,14 +1845,13 @@ fn agent_cmd_container_stats(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .stats_container(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1232,14 +1874,13 @@ fn agent_cmd_container_pause(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .pause_container(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1262,14 +1903,13 @@ fn agent_cmd_container_resume(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .resume_container(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1285,14 +1925,13 @@ fn agent_cmd_container_start(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .start_container(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1316,14 +1955,13 @@ fn agent_cmd_sandbox_get_guest_details(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .get_guest_details(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1339,14 +1977,13 @@ fn agent_cmd_sandbox_get_ip_tables(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .get_ip_tables(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1372,14 +2009,13 @@ fn agent_cmd_container_wait_process(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .wait_process(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1415,14 +2051,13 @@ fn agent_cmd_container_signal_process(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .signal_process(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1438,7 +2073,7 @@ fn agent_cmd_sandbox_update_interface(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
     let reply = client
         .update_interface(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
@@ -1446,8 +2081,7 @@ fn agent_cmd_sandbox_update_interface(
     // FIXME: Implement 'UpdateInterface' fully.
     eprintln!("FIXME: 'UpdateInterface' not fully implemented");
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1463,7 +2097,7 @@ fn agent_cmd_sandbox_update_routes(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .update_routes(ctx, &req)
@@ -1472,8 +2106,7 @@ fn agent_cmd_sandbox_update_routes(
     // FIXME: Implement 'UpdateRoutes' fully.
     eprintln!("FIXME: 'UpdateRoutes' not fully implemented");
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1489,14 +2122,13 @@ fn agent_cmd_sandbox_list_interfaces(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .list_interfaces(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1512,14 +2144,13 @@ fn agent_cmd_sandbox_list_routes(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .list_routes(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1564,14 +2195,13 @@ fn agent_cmd_container_tty_win_resize(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .tty_win_resize(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1597,14 +2227,13 @@ fn agent_cmd_container_close_stdin(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .close_stdin(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1639,14 +2268,13 @@ fn agent_cmd_container_read_stdout(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .read_stdout(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1681,14 +2309,13 @@ fn agent_cmd_container_read_stderr(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .read_stderr(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1718,14 +2345,13 @@ fn agent_cmd_container_write_stdin(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .write_stdin(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1741,14 +2367,13 @@ fn agent_cmd_sandbox_get_metrics(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .get_metrics(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1764,14 +2389,13 @@ fn agent_cmd_sandbox_get_oom_event(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .get_oom_event(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1787,14 +2411,13 @@ fn agent_cmd_sandbox_get_volume_stats(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .get_volume_stats(ctx, &req)
         .map_err(|e| anyhow!(e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1817,7 +2440,7 @@ fn agent_cmd_sandbox_copy_file(
             .copy_file(clone_context(ctx), &req)
             .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-        info!(sl!(), "response received"; "response" => format!("{:?}", reply));
+        utils::log_response(&reply);
 
         return Ok(());
     }
@@ -1841,7 +2464,7 @@ fn agent_cmd_sandbox_copy_file(
             .copy_file(clone_context(ctx), &req)
             .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-        info!(sl!(), "response received"; "response" => format!("{:?}", reply));
+        utils::log_response(&reply);
 
         remaining_bytes -= copy_size;
         offset += copy_size;
@@ -1871,14 +2494,13 @@ fn agent_cmd_sandbox_reseed_random_dev(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .reseed_random_dev(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1928,14 +2550,13 @@ fn agent_cmd_sandbox_online_cpu_mem(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .online_cpu_mem(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1975,14 +2596,13 @@ fn agent_cmd_sandbox_set_guest_date_time(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .set_guest_date_time(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -1998,14 +2618,13 @@ fn agent_cmd_sandbox_set_ip_tables(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .set_ip_tables(ctx, &req)
         .map_err(|e| anyhow!(e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -2024,14 +2643,13 @@ fn agent_cmd_sandbox_add_arp_neighbors(
     // FIXME: Implement fully.
     eprintln!("FIXME: 'AddARPNeighbors' not fully implemented");
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .add_arp_neighbors(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -2058,14 +2676,13 @@ fn agent_cmd_sandbox_update_container(
     // FIXME: Implement fully
     eprintln!("FIXME: 'UpdateContainer' not fully implemented");
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .update_container(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -2109,14 +2726,13 @@ fn agent_cmd_sandbox_mem_hotplug_by_probe(
         Ok(())
     });
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .mem_hotplug_by_probe(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -2151,6 +2767,56 @@ fn builtin_cmd_echo(args: &str) -> (Result<()>, bool) {
     (Ok(()), false)
 }
 
+fn builtin_cmd_resize(args: &str) -> (Result<()>, bool) {
+    let vm_instance = match TEST_VM.lock().unwrap().clone() {
+        Some(vm_instance) => vm_instance,
+        None => return (Err(anyhow!("resize requires a test vm (--vm)")), false),
+    };
+
+    let (vcpus, memory_mb) = match parse_resize_args(args) {
+        Ok(targets) => targets,
+        Err(e) => return (Err(e), false),
+    };
+
+    (resize_test_vm(&vm_instance, vcpus, memory_mb), false)
+}
+
+// Parse the "vcpus=N memory=SIZE_MB" arguments of the resize command, at
+// least one of them being required
+fn parse_resize_args(args: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let mut vcpus = None;
+    let mut memory_mb = None;
+
+    for field in args.split_whitespace() {
+        let (name, value) = field
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid resize argument {:?}: expected NAME=VALUE", field))?;
+
+        let target = match value.parse::<u32>() {
+            Ok(target) if target > 0 => target,
+            _ => {
+                return Err(anyhow!(
+                    "{} must be a positive integer, got {:?}",
+                    name,
+                    value
+                ))
+            }
+        };
+
+        match name {
+            "vcpus" => vcpus = Some(target),
+            "memory" => memory_mb = Some(target),
+            _ => return Err(anyhow!("invalid resize argument {:?}", name)),
+        }
+    }
+
+    if vcpus.is_none() && memory_mb.is_none() {
+        return Err(anyhow!("resize expects vcpus=N and/or memory=SIZE_MB"));
+    }
+
+    Ok((vcpus, memory_mb))
+}
+
 fn builtin_cmd_quit(_args: &str) -> (Result<()>, bool) {
     (Ok(()), true)
 }
@@ -2197,7 +2863,7 @@ fn agent_cmd_sandbox_add_swap(
 
     let ctx = clone_context(ctx);
 
-    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+    utils::log_request(&req);
 
     let reply = client
         .add_swap(ctx, &req)
@@ -2206,8 +2872,7 @@ fn agent_cmd_sandbox_add_swap(
     // FIXME: Implement 'AddSwap' fully.
     eprintln!("FIXME: 'AddSwap' not fully implemented");
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -2231,8 +2896,7 @@ fn agent_cmd_sandbox_set_policy(
         .set_policy(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -2255,8 +2919,7 @@ fn agent_cmd_mem_agent_memcg_set(
         .mem_agent_memcg_set(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
 
     Ok(())
 }
@@ -2278,8 +2941,773 @@ fn agent_cmd_mem_agent_compact_set(
         .mem_agent_compact_set(ctx, &req)
         .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
 
-    info!(sl!(), "response received";
-        "response" => format!("{:?}", reply));
+    utils::log_response(&reply);
+
+    Ok(())
+}
+
+// Run the specified command in an existing container and return its
+// exit status along with everything it wrote to stdout.
+fn guest_exec(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    cid: &str,
+    cmd: &[&str],
+) -> Result<(i32, String)> {
+    guest_exec_output(ctx, client, cid, cmd).map(|(status, stdout, _)| (status, stdout))
+}
+
+// Same as guest_exec, also returning what the command wrote to stderr. The
+// streams are read one after the other, so this is only meant for commands
+// with little output.
+fn guest_exec_output(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    cid: &str,
+    cmd: &[&str],
+) -> Result<(i32, String, String)> {
+    let exec_id = utils::random_container_id();
+
+    let process = protocols::oci::Process {
+        Args: cmd.iter().map(|s| s.to_string()).collect(),
+        Env: vec![GUEST_EXEC_PATH_ENV.to_string()],
+        Cwd: "/".to_string(),
+        ..Default::default()
+    };
+
+    let mut req = ExecProcessRequest::default();
+    req.set_container_id(cid.to_string());
+    req.set_exec_id(exec_id.clone());
+    req.set_process(process);
+
+    utils::log_request(&req);
+
+    client
+        .exec_process(clone_context(ctx), &req)
+        .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
+
+    let mut read_req = ReadStreamRequest::default();
+    read_req.set_container_id(cid.to_string());
+    read_req.set_exec_id(exec_id.clone());
+    read_req.set_len(GUEST_EXEC_READ_LEN);
+
+    // The agent returns an empty buffer once the process closed the stream
+    let mut output = Vec::new();
+    while let Ok(reply) = client.read_stdout(clone_context(ctx), &read_req) {
+        if reply.data.is_empty() {
+            break;
+        }
+        output.extend_from_slice(&reply.data);
+    }
+
+    let mut errors = Vec::new();
+    while let Ok(reply) = client.read_stderr(clone_context(ctx), &read_req) {
+        if reply.data.is_empty() {
+            break;
+        }
+        errors.extend_from_slice(&reply.data);
+    }
+
+    let mut wait_req = WaitProcessRequest::default();
+    wait_req.set_container_id(cid.to_string());
+    wait_req.set_exec_id(exec_id);
+
+    let reply = client
+        .wait_process(clone_context(ctx), &wait_req)
+        .map_err(|e| anyhow!("{:?}", e).context(ERR_API_FAILED))?;
+
+    debug!(
+        sl!(),
+        "guest command {:?} exited with {}", cmd, reply.status
+    );
+
+    Ok((
+        reply.status,
+        String::from_utf8_lossy(&output).to_string(),
+        String::from_utf8_lossy(&errors).to_string(),
+    ))
+}
+
+// Add the guest kernel messages related to storage to the error of a
+// failed storage request, as the reason of a failed mount is often only
+// logged there. The messages are read through a container created earlier,
+// the error is returned unchanged if there is none or nothing matches.
+fn with_storage_diagnostics(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    options: &Options,
+    err: anyhow::Error,
+) -> anyhow::Error {
+    let cid = match options.get("cid") {
+        Some(cid) => cid,
+        None => return err,
+    };
+
+    let output = match guest_exec(ctx, client, cid, &["dmesg"]) {
+        Ok((0, output)) => output,
+        Ok((status, _)) => {
+            debug!(sl!(), "guest dmesg exited with {}", status);
+            return err;
+        }
+        Err(e) => {
+            debug!(sl!(), "failed to read guest dmesg: {:?}", e);
+            return err;
+        }
+    };
+
+    let lines: Vec<&str> = output
+        .lines()
+        .filter(|l| {
+            let l = l.to_lowercase();
+            STORAGE_DMESG_KEYWORDS.iter().any(|k| l.contains(k))
+        })
+        .collect();
+
+    if lines.is_empty() {
+        return err;
+    }
+
+    let start = lines.len().saturating_sub(STORAGE_DMESG_MAX_LINES);
+
+    err.context(format!(
+        "guest kernel messages:\n{}",
+        lines[start..].join("\n")
+    ))
+}
+
+// Measure the sequential read throughput of a storage mounted in the
+// guest, bypassing the guest page cache. A test file of size_mb MB is
+// written below the mount point, read back and removed.
+fn agent_cmd_container_measure_read_throughput(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    _health: &HealthClient,
+    options: &mut Options,
+    args: &str,
+) -> Result<()> {
+    let cid = utils::get_option("cid", options, args)?;
+    let path = utils::get_option("path", options, args)?;
+    let size_mb = utils::get_option("size_mb", options, args)?;
+
+    if path.is_empty() {
+        return Err(anyhow!("MeasureReadThroughput needs a mount path"));
+    }
+
+    let size_mb = match size_mb.as_str() {
+        "" => DEFAULT_THROUGHPUT_SIZE_MB,
+        s => s
+            .parse::<u32>()
+            .map_err(|e| anyhow!("invalid size_mb {:?}: {:?}", s, e))?,
+    };
+
+    let test_file = format!("{}/{}", path.trim_end_matches('/'), THROUGHPUT_TEST_FILE);
+    let count = format!("count={}", size_mb);
+
+    let (status, _, errors) = guest_exec_output(
+        ctx,
+        client,
+        &cid,
+        &[
+            "dd",
+            "if=/dev/zero",
+            &format!("of={}", test_file),
+            "bs=1M",
+            &count,
+            "conv=fsync",
+        ],
+    )?;
+    if status != 0 {
+        return Err(anyhow!(
+            "failed to write {}: exit status {}: {}",
+            test_file,
+            status,
+            errors.trim()
+        ));
+    }
+
+    let result = guest_exec_output(
+        ctx,
+        client,
+        &cid,
+        &[
+            "dd",
+            &format!("if={}", test_file),
+            "of=/dev/null",
+            "bs=1M",
+            &count,
+            "iflag=direct",
+        ],
+    );
+
+    // remove the test file whatever the outcome of the read
+    if let Err(e) = guest_exec(ctx, client, &cid, &["rm", "-f", &test_file]) {
+        warn!(sl!(), "failed to remove {}: {:?}", test_file, e);
+    }
+
+    let (status, _, errors) = result?;
+    if status != 0 {
+        return Err(anyhow!(
+            "failed to read {}: exit status {}: {}",
+            test_file,
+            status,
+            errors.trim()
+        ));
+    }
+
+    // dd reports its statistics on stderr
+    let mb_per_sec = utils::parse_dd_throughput(&errors)
+        .ok_or_else(|| anyhow!("failed to parse dd output: {:?}", errors.trim()))?;
+
+    info!(sl!(), "read throughput";
+        "path" => &path,
+        "size-mb" => size_mb,
+        "mb-per-sec" => format!("{:.1}", mb_per_sec));
+
+    utils::record_probe_result(ProbeResult::ReadThroughput {
+        container_id: cid,
+        path,
+        size_mb,
+        mb_per_sec,
+    });
+
+    Ok(())
+}
+
+// Check that the guest enumerated a device at each of the specified (comma
+// separated) PCI paths, defaulting to the ones recorded for the disks
+// attached to the test vm. The guest sysfs is walked from inside a container, one PCI path
+// slot at a time starting from the root bus.
+fn agent_cmd_container_verify_pci_path(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    _health: &HealthClient,
+    options: &mut Options,
+    args: &str,
+) -> Result<()> {
+    let cid = utils::get_option("cid", options, args)?;
+    let mut pci_paths = utils::get_option("pci_path", options, args)?;
+
+    if pci_paths.is_empty() {
+        if let Some(storages) = options.get("disk-storages") {
+            let storages: Vec<Storage> = serde_json::from_str(storages)
+                .map_err(|e| anyhow!("invalid disk storages: {:?}", e))?;
+
+            pci_paths = storages
+                .iter()
+                .filter(|s| s.driver == DRIVER_BLK_PCI_TYPE)
+                .map(|s| s.source.clone())
+                .collect::<Vec<String>>()
+                .join(",");
+        }
+    }
+
+    if pci_paths.is_empty() {
+        return Err(anyhow!("VerifyPciPath needs PCI paths to check"));
+    }
+
+    for pci_path in pci_paths.split(',') {
+        let mut sysfs_path = GUEST_PCI_ROOT_PATH.to_string();
+
+        for slot in pci_path.split('/') {
+            let (status, listing) = guest_exec(ctx, client, &cid, &["ls", &sysfs_path])?;
+            if status != 0 {
+                return Err(anyhow!(
+                    "failed to list guest {}: exit status {}",
+                    sysfs_path,
+                    status
+                ));
+            }
+
+            let device = utils::find_pci_slot_device(&listing, slot).ok_or_else(|| {
+                anyhow!(
+                    "PCI path {}: no guest device at slot {} under {}",
+                    pci_path,
+                    slot,
+                    sysfs_path
+                )
+            })?;
+
+            sysfs_path = format!("{}/{}", sysfs_path, device);
+        }
+
+        info!(sl!(), "PCI path verified";
+            "pci-path" => pci_path,
+            "sysfs-path" => &sysfs_path);
+
+        utils::record_probe_result(ProbeResult::PciPath {
+            container_id: cid.clone(),
+            pci_path: pci_path.to_string(),
+            sysfs_path,
+        });
+    }
+
+    Ok(())
+}
+
+// Check the xattr passthrough of virtio-fs: a random value is set as an
+// xattr of a file created on the host in the shared rootfs of the
+// container, and read back from inside the container with getfattr.
+// virtiofsd only passes xattrs through when started with --xattr, see
+// virtio_fs_extra_args in the hypervisor configuration.
+// Wait for a container created with a ready marker to have started, by
+// polling for the marker it creates in its shared rootfs. This does not
+// involve the agent, which keeps the check independent of its API.
+fn agent_cmd_container_wait_ready(
+    _ctx: &Context,
+    _client: &AgentServiceClient,
+    _health: &HealthClient,
+    options: &mut Options,
+    args: &str,
+) -> Result<()> {
+    let cid = utils::get_option("cid", options, args)?;
+
+    let timeout = match utils::get_option("timeout", options, args) {
+        Ok(t) if !t.is_empty() => t,
+        _ => DEFAULT_READY_TIMEOUT.to_string(),
+    };
+    let timeout = Duration::from_nanos(utils::human_time_to_ns(&timeout)? as u64);
+
+    let share_fs_path = match options.get("shared-path") {
+        Some(p) => p.to_string(),
+        None => {
+            return Err(anyhow!(
+                "WaitContainerReady requires a test vm sharing files"
+            ))
+        }
+    };
+
+    let marker = vm::vm_utils::ready_marker_host_path(&share_fs_path, &cid);
+    let start = Instant::now();
+
+    while !marker.exists() {
+        if start.elapsed() >= timeout {
+            return Err(anyhow!(
+                "container {} not ready after {:?}: no marker {:?}",
+                cid,
+                timeout,
+                marker
+            ));
+        }
+
+        sleep(READY_MARKER_INTERVAL);
+    }
+
+    info!(sl!(), "container ready";
+        "container" => &cid,
+        "elapsed" => format!("{:?}", start.elapsed()));
+
+    Ok(())
+}
+
+// Check that a storage mounted read-only in the guest rejects writes, by
+// attempting to create a file at the specified path in the container, which
+// has to fail with EROFS.
+fn agent_cmd_container_verify_read_only(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    _health: &HealthClient,
+    options: &mut Options,
+    args: &str,
+) -> Result<()> {
+    let cid = utils::get_option("cid", options, args)?;
+    let path = utils::get_option("path", options, args)?;
+
+    let file = format!("{}/{}", path.trim_end_matches('/'), READ_ONLY_TEST_FILE);
+
+    let (status, _, errors) = guest_exec_output(ctx, client, &cid, &["touch", &file])?;
+
+    if status == 0 {
+        if let Err(e) = guest_exec(ctx, client, &cid, &["rm", "-f", &file]) {
+            warn!(sl!(), "failed to remove {}: {:?}", file, e);
+        }
+
+        return Err(anyhow!(
+            "read-only check failed: {} is writable in container {}",
+            path,
+            cid
+        ));
+    }
+
+    if !errors.contains(READ_ONLY_ERROR) {
+        return Err(anyhow!(
+            "read-only check failed: write to {} was rejected for another reason: exit status {}: {}",
+            path,
+            status,
+            errors.trim()
+        ));
+    }
+
+    info!(sl!(), "read-only storage verified";
+        "container" => &cid,
+        "path" => &path);
+
+    Ok(())
+}
+
+// Check that two containers see the same storage at the specified path: a
+// file written by the first one is read back by the peer container, from
+// the same device and inode.
+fn agent_cmd_container_verify_shared_storage(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    _health: &HealthClient,
+    options: &mut Options,
+    args: &str,
+) -> Result<()> {
+    let cid = utils::get_option("cid", options, args)?;
+    let peer_cid = utils::get_option("peer_cid", options, args)?;
+    let path = utils::get_option("path", options, args)?;
+
+    if cid == peer_cid {
+        return Err(anyhow!("peer container must differ from container {}", cid));
+    }
+
+    let file = format!(
+        "{}/{}",
+        path.trim_end_matches('/'),
+        SHARED_STORAGE_TEST_FILE
+    );
+    let token = utils::generate_random_hex_string(SHARED_STORAGE_TOKEN_LEN);
+
+    let (status, _, errors) = guest_exec_output(
+        ctx,
+        client,
+        &cid,
+        &["sh", "-c", "printf %s \"$1\" > \"$2\"", "sh", &token, &file],
+    )?;
+    if status != 0 {
+        return Err(anyhow!(
+            "failed to write {} in container {}: exit status {}: {}",
+            file,
+            cid,
+            status,
+            errors.trim()
+        ));
+    }
+
+    let result = verify_shared_file(ctx, client, &cid, &peer_cid, &file, &token);
+
+    match guest_exec(ctx, client, &cid, &["rm", "-f", &file]) {
+        Ok((0, _)) => (),
+        Ok((status, _)) => warn!(sl!(), "failed to remove {}: exit status {}", file, status),
+        Err(e) => warn!(sl!(), "failed to remove {}: {:?}", file, e),
+    }
+
+    let device = result?;
+
+    info!(sl!(), "shared storage verified";
+        "container" => &cid,
+        "peer-container" => &peer_cid,
+        "path" => &path,
+        "device" => device);
+
+    Ok(())
+}
+
+// Read the file written by the container from the peer container, checking
+// it holds the token and is the same file for both. Returns the device and
+// inode of the file.
+fn verify_shared_file(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    cid: &str,
+    peer_cid: &str,
+    file: &str,
+    token: &str,
+) -> Result<String> {
+    let (status, data, errors) = guest_exec_output(ctx, client, peer_cid, &["cat", file])?;
+    if status != 0 {
+        return Err(anyhow!(
+            "failed to read {} in peer container {}: exit status {}: {}",
+            file,
+            peer_cid,
+            status,
+            errors.trim()
+        ));
+    }
+
+    if data != token {
+        return Err(anyhow!(
+            "{} mismatch: container {} wrote {:?}, peer container {} read {:?}",
+            file,
+            cid,
+            token,
+            peer_cid,
+            data
+        ));
+    }
+
+    let stat = ["stat", "-c", "%d:%i", file];
+
+    let (status, device) = guest_exec(ctx, client, cid, &stat)?;
+    if status != 0 {
+        return Err(anyhow!("failed to stat {}: exit status {}", file, status));
+    }
+
+    let (status, peer_device) = guest_exec(ctx, client, peer_cid, &stat)?;
+    if status != 0 {
+        return Err(anyhow!(
+            "failed to stat {} in peer container {}: exit status {}",
+            file,
+            peer_cid,
+            status
+        ));
+    }
+
+    let (device, peer_device) = (device.trim(), peer_device.trim());
+    if device != peer_device {
+        return Err(anyhow!(
+            "{} is on device:inode {} in container {} but {} in peer container {}",
+            file,
+            device,
+            cid,
+            peer_device,
+            peer_cid
+        ));
+    }
+
+    Ok(device.to_string())
+}
+
+fn agent_cmd_container_verify_xattr(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    _health: &HealthClient,
+    options: &mut Options,
+    args: &str,
+) -> Result<()> {
+    let cid = utils::get_option("cid", options, args)?;
+
+    let share_fs_path = match options.get("shared-path") {
+        Some(p) => p.to_string(),
+        None => return Err(anyhow!("VerifyXattr requires a test vm sharing the rootfs")),
+    };
+
+    let value = utils::generate_random_hex_string(XATTR_TEST_VALUE_LEN);
+    let (host_file, guest_file) =
+        vm::vm_utils::create_xattr_file(&share_fs_path, &cid, XATTR_TEST_NAME, &value)?;
+
+    let result = guest_exec_output(
+        ctx,
+        client,
+        &cid,
+        &[
+            "getfattr",
+            "--only-values",
+            "--absolute-names",
+            "-n",
+            XATTR_TEST_NAME,
+            &guest_file,
+        ],
+    );
+
+    if let Err(e) = fs::remove_file(&host_file) {
+        warn!(sl!(), "failed to remove {:?}: {:?}", host_file, e);
+    }
+
+    let (status, output, errors) = result?;
+    if status != 0 {
+        return Err(anyhow!(
+            "failed to read xattr {} of {}: exit status {}: {}",
+            XATTR_TEST_NAME,
+            guest_file,
+            status,
+            errors.trim()
+        ));
+    }
+
+    if output != value {
+        return Err(anyhow!(
+            "xattr {} mismatch: set {:?}, guest read {:?}",
+            XATTR_TEST_NAME,
+            value,
+            output
+        ));
+    }
+
+    info!(sl!(), "xattr passthrough verified"; "container" => &cid);
+
+    Ok(())
+}
+
+// Report the propagation of the specified (comma separated) mounts as seen
+// from inside a container, optionally checking they match the expected one.
+fn agent_cmd_container_get_mount_propagation(
+    ctx: &Context,
+    client: &AgentServiceClient,
+    _health: &HealthClient,
+    options: &mut Options,
+    args: &str,
+) -> Result<()> {
+    let cid = utils::get_option("cid", options, args)?;
+    let mounts = utils::get_option("mounts", options, args)?;
+    let expected = utils::get_option("propagation", options, args)?;
+
+    if mounts.is_empty() {
+        return Err(anyhow!("GetMountPropagation needs mounts to check"));
+    }
+
+    let (status, mountinfo) = guest_exec(ctx, client, &cid, &["cat", "/proc/self/mountinfo"])?;
+    if status != 0 {
+        return Err(anyhow!(
+            "failed to read guest mountinfo: exit status {}",
+            status
+        ));
+    }
+
+    for mount in mounts.split(',') {
+        let propagation = utils::get_mount_propagation(&mountinfo, mount)
+            .ok_or_else(|| anyhow!("mount {:?} not found in guest", mount))?;
+
+        info!(sl!(), "mount propagation";
+            "mount" => mount,
+            "propagation" => propagation.join(","));
+
+        utils::record_probe_result(ProbeResult::MountPropagation {
+            container_id: cid.clone(),
+            mount: mount.to_string(),
+            propagation: propagation.clone(),
+        });
+
+        if !expected.is_empty() && !propagation.contains(&expected) {
+            return Err(anyhow!(
+                "mount {}: expected propagation {:?}, found {:?}",
+                mount,
+                expected,
+                propagation
+            ));
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_server_address() {
+        #[derive(Debug)]
+        struct TestData<'a> {
+            socket_addr: &'a str,
+            agent_port: u32,
+            result: Result<(String, bool)>,
+        }
+
+        let tests = &[
+            TestData {
+                socket_addr: "vsock://3",
+                agent_port: 1024,
+                result: Ok(("vsock://3:1024".to_string(), false)),
+            },
+            TestData {
+                socket_addr: "vsock://3",
+                agent_port: 2048,
+                result: Ok(("vsock://3:2048".to_string(), false)),
+            },
+            TestData {
+                socket_addr: "vsock://3:4096",
+                agent_port: 2048,
+                result: Ok(("vsock://3:4096".to_string(), false)),
+            },
+            TestData {
+                socket_addr: "hvsock:///run/vc/kata.hvsock",
+                agent_port: 2048,
+                result: Ok(("unix:///run/vc/kata.hvsock".to_string(), true)),
+            },
+            TestData {
+                socket_addr: "vsock:3",
+                agent_port: 1024,
+                result: Err(anyhow!("invalid agent socket address: \"vsock:3\"")),
+            },
+            TestData {
+                socket_addr: "tcp://127.0.0.1",
+                agent_port: 1024,
+                result: Err(anyhow!("unsupported agent socket scheme: \"tcp\"")),
+            },
+        ];
+
+        for (i, d) in tests.iter().enumerate() {
+            let msg = format!("test[{}]: {:?}", i, d);
+
+            let result = agent_server_address(d.socket_addr, d.agent_port);
+
+            let msg = format!("{}, result: {:?}", msg, result);
+
+            if d.result.is_err() {
+                assert!(result.is_err(), "{}", msg);
+
+                let expected_error = format!("{}", d.result.as_ref().unwrap_err());
+                let actual_error = format!("{}", result.unwrap_err());
+                assert!(actual_error == expected_error, "{}", msg);
+
+                continue;
+            }
+
+            assert_eq!(result.unwrap(), *d.result.as_ref().unwrap(), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn test_parse_resize_args() {
+        #[derive(Debug)]
+        struct TestData<'a> {
+            args: &'a str,
+            result: Result<(Option<u32>, Option<u32>)>,
+        }
+
+        let tests = &[
+            TestData {
+                args: "vcpus=4",
+                result: Ok((Some(4), None)),
+            },
+            TestData {
+                args: "memory=4096",
+                result: Ok((None, Some(4096))),
+            },
+            TestData {
+                args: "memory=2048 vcpus=2",
+                result: Ok((Some(2), Some(2048))),
+            },
+            TestData {
+                args: "",
+                result: Err(anyhow!("resize expects vcpus=N and/or memory=SIZE_MB")),
+            },
+            TestData {
+                args: "vcpus",
+                result: Err(anyhow!(
+                    "invalid resize argument \"vcpus\": expected NAME=VALUE"
+                )),
+            },
+            TestData {
+                args: "vcpus=0",
+                result: Err(anyhow!("vcpus must be a positive integer, got \"0\"")),
+            },
+            TestData {
+                args: "disk=10",
+                result: Err(anyhow!("invalid resize argument \"disk\"")),
+            },
+        ];
+
+        for (i, d) in tests.iter().enumerate() {
+            let msg = format!("test[{}]: {:?}", i, d);
+
+            let result = parse_resize_args(d.args);
+
+            let msg = format!("{}, result: {:?}", msg, result);
+
+            if d.result.is_err() {
+                assert!(result.is_err(), "{}", msg);
+
+                let expected_error = format!("{}", d.result.as_ref().unwrap_err());
+                let actual_error = format!("{}", result.unwrap_err());
+                assert!(actual_error == expected_error, "{}", msg);
+
+                continue;
+            }
+
+            assert_eq!(result.unwrap(), *d.result.as_ref().unwrap(), "{}", msg);
+        }
+    }
+}
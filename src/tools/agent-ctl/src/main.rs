@@ -6,11 +6,13 @@
 
 #[macro_use]
 extern crate lazy_static;
-use crate::types::Config;
+use crate::types::{Config, ProbeResult};
 use anyhow::{anyhow, Result};
 use clap::{crate_name, crate_version, Arg, Command};
 use std::io;
+use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
 
 // Convenience macro to obtain the scope logger
 #[macro_export]
@@ -42,9 +44,19 @@ const WARNING_TEXT: &str = r#"WARNING:
     irrevocably other parts of the system or even kill a running container or
     sandbox."#;
 
+// Output formats for the results of diagnostic probes
+const OUTPUT_FORMAT_TEXT: &str = "text";
+const OUTPUT_FORMAT_JSON: &str = "json";
+
 // The VSOCK port number the Kata agent uses to listen to API requests on.
 const DEFAULT_KATA_AGENT_API_VSOCK_PORT: &str = "1024";
 
+// Maximum time to wait for the agent of a test VM to answer.
+const DEFAULT_AGENT_WAIT_TIMEOUT: &str = "30s";
+
+// virtio-fs cache modes understood by the virtiofs daemon and dragonball
+const VIRTIO_FS_CACHE_MODES: &[&str] = &["never", "auto", "always"];
+
 fn make_examples_text(program_name: &str) -> String {
     let abstract_server_address = "unix://@/foo/bar/abstract.socket";
     let bundle = "$bundle_dir";
@@ -78,6 +90,62 @@ fn make_examples_text(program_name: &str) -> String {
 
   $ {program_name} connect --vm qemu --cmd Check
 
+- Boot up a QEMU test VM using another Kata configuration file:
+
+  $ {program_name} connect --vm qemu --hypervisor-config /tmp/configuration-qemu-test.toml --cmd Check
+
+- Boot up a QEMU test VM, giving the hypervisor up to 30 seconds to start it:
+
+  $ {program_name} connect --vm qemu --boot-timeout 30s --cmd Check
+
+- Boot up a QEMU test VM, pausing it for 5 seconds before checking the agent is still reachable:
+
+  $ {program_name} connect --vm qemu --pause-vm 5s --cmd Check
+
+- Boot up a QEMU test VM, hotplug vcpus and memory into it and have the agent online them:
+
+  $ {program_name} connect --vm qemu --resize-vcpus 4 --resize-memory 4096 --cmd OnlineCPUMem
+
+- Boot up a QEMU test VM and resize it in between the commands, with the resize built-in command:
+
+  $ {program_name} connect --vm qemu --cmd 'resize vcpus=4' --cmd OnlineCPUMem --cmd 'resize vcpus=2'
+
+- Boot up a QEMU test VM with a network device on the tap0 tap device:
+
+  $ {program_name} connect --vm qemu --tap tap0 --cmd Check
+
+- Boot up a QEMU test VM with the PCI device 0000:3b:00.0, bound to vfio-pci, passed through:
+
+  $ {program_name} connect --vm qemu --vfio 0000:3b:00.0 --cmd Check
+
+- Print the effective QEMU configuration, with the test VM options applied:
+
+  $ {program_name} connect --vm qemu --scratch-disk 64:ext4 --dump-config
+
+- Show the versions of the hypervisor and virtiofsd binaries configured for QEMU:
+
+  $ {program_name} binary-versions --vm qemu
+
+- Boot a QEMU test VM saving its state, and tear it down later (also from another shell):
+
+  $ {program_name} connect --vm qemu --state-file /tmp/testvm.json --interactive
+  $ {program_name} teardown /tmp/testvm.json
+
+- Boot a QEMU test VM in a named session, list the sessions and kill it:
+
+  $ {program_name} connect --vm qemu --session test1 --interactive
+  $ {program_name} sessions
+  $ {program_name} kill-session test1
+
+- Leave a QEMU test VM that reports no agent socket running for inspection, and kill it:
+
+  $ {program_name} connect --vm qemu --session debug1 --keep-vm-without-agent
+  $ {program_name} kill-session debug1
+
+- Measure the time taken to boot a Cloud Hypervisor test VM until its agent answers:
+
+  $ {program_name} measure-boot --vm clh
+
 - Query the agent environment:
 
   $ {program_name} connect --server-address "{vsock_server_address}" --cmd GetGuestDetails
@@ -111,6 +179,10 @@ fn make_examples_text(program_name: &str) -> String {
   $ echo '{{"mem_block_size": true}}' > /tmp/api.json
   $ {program_name} connect --server-address "{vsock_server_address}" -c 'GetGuestDetails file:///tmp/api.json'
 
+- Query guest details, reading the API request object in JSON format from stdin:
+
+  $ echo '{{"mem_block_size": true}}' | {program_name} connect --server-address "{vsock_server_address}" -c 'GetGuestDetails -'
+
 - Send a 'SIGUSR1' signal to a container process:
 
   $ {program_name} connect --server-address "{vsock_server_address}" --cmd 'SignalProcess signal=usr1 sid={sandbox_id} cid={container_id}'
@@ -121,6 +193,67 @@ fn make_examples_text(program_name: &str) -> String {
   $ {program_name} connect --server-address "{vsock_server_address}" --bundle-dir {bundle:?} --cmd CreateContainer
   $ {program_name} connect --server-address "{vsock_server_address}" --cmd DestroySandbox
 
+- Check the propagation of a mount as seen from inside a container:
+
+  $ {program_name} connect --server-address "{vsock_server_address}" --cmd 'GetMountPropagation cid={container_id} mounts=/data propagation=shared'
+
+- Boot up a QEMU test VM with a scratch disk attached over virtio-scsi rather than the configured block driver:
+
+  $ {program_name} connect --vm qemu --scratch-disk 64:ext4:block-driver=virtio-scsi --bundle-dir {bundle:?} --cmd CreateSandbox
+
+- Check the guest enumerated the scratch disk devices at their PCI paths:
+
+  $ {program_name} connect --vm qemu --scratch-disk 64:ext4 --bundle-dir {bundle:?} --cmd CreateSandbox CreateContainer 'VerifyPciPath cid={container_id}'
+
+- Measure the direct read throughput of a storage mounted in a container:
+
+  $ {program_name} connect --server-address "{vsock_server_address}" --cmd 'MeasureReadThroughput cid={container_id} path=/data size_mb=256'
+
+- Wait for a container created with "ready_marker": true in its input to have started, through its shared rootfs:
+
+  $ {program_name} connect --vm qemu --bundle-dir {bundle:?} --cmd CreateSandbox 'CreateContainer file:///tmp/c1.json' 'StartContainer json://{{"container_id": "c1"}}' 'WaitContainerReady cid=c1 timeout=10s'
+
+- Check a read-only scratch disk, bound in the container volumes at /data, rejects writes:
+
+  $ {program_name} connect --vm qemu --scratch-disk 64:ext4:ro --bundle-dir {bundle:?} --cmd CreateSandbox 'CreateContainer file:///tmp/c1.json' 'VerifyReadOnly cid=c1 path=/data'
+
+- Check two containers share a scratch disk, each container input listing
+  it in its volumes, e.g. {{"source": "/run/kata-containers/scratch/0", "destination": "/data"}}:
+
+  $ {program_name} connect --vm qemu --scratch-disk 64:ext4 --bundle-dir {bundle:?} --cmd CreateSandbox 'CreateContainer file:///tmp/c1.json' 'CreateContainer file:///tmp/c2.json' 'VerifySharedStorage cid=c1 peer_cid=c2 path=/data'
+
+- Bind a scratch disk in a container with extra mount options, the container
+  input volumes being e.g. {{"source": "/run/kata-containers/scratch/0", "destination": "/data", "options": ["nosuid", "noexec"]}}:
+
+  $ {program_name} connect --vm qemu --scratch-disk 64:ext4 --bundle-dir {bundle:?} --cmd CreateSandbox 'CreateContainer file:///tmp/c1.json'
+
+- Boot up a QEMU test VM whose virtio-fs share caches everything in the guest:
+
+  $ {program_name} connect --vm qemu --virtio-fs-cache always --cmd Check
+
+- Share the /srv/dataset host directory with a QEMU test VM over a virtio-fs
+  device of its own, mounted at /data in the guest on sandbox creation:
+
+  $ {program_name} connect --vm qemu --virtiofs-share /srv/dataset:dataset:/data --bundle-dir {bundle:?} --cmd CreateSandbox 'CreateContainer file:///tmp/c1.json'
+
+- Mount a 64MiB tmpfs at /run/kata-containers/ephemeral/0 in the guest on
+  sandbox creation, to be bound in the container volumes:
+
+  $ {program_name} connect --vm qemu --tmpfs 64m:1777 --bundle-dir {bundle:?} --cmd CreateSandbox 'CreateContainer file:///tmp/c1.json'
+
+- Attach an ext4 image as an nvdimm device, mounted by the agent at
+  /run/kata-containers/pmem on sandbox creation:
+
+  $ {program_name} connect --vm qemu --pmem /tmp/pmem.img:ext4 --bundle-dir {bundle:?} --cmd CreateSandbox 'CreateContainer file:///tmp/c1.json'
+
+- Check xattrs set on the host are seen through virtio-fs in a container:
+
+  $ {program_name} connect --vm qemu --bundle-dir {bundle:?} --cmd CreateSandbox CreateContainer 'VerifyXattr cid={container_id}'
+
+- Check the test VM configuration and devices without booting the VM:
+
+  $ {program_name} connect --vm qemu --scratch-disk 64:ext4 --prepare-only
+
 - Create a Container using a custom configuration file:
 
   $ {program_name} connect --server-address "{vsock_server_address}" --bundle-dir {bundle:?} --cmd 'CreateContainer spec={config_file_uri}'
@@ -155,9 +288,28 @@ fn connect(name: &str, global_args: clap::ArgMatches) -> Result<()> {
         return Err(anyhow!("need server address"));
     }
 
+    let hypervisor_config_path = args
+        .get_one::<String>("hypervisor-config")
+        .map(|s| s.as_str())
+        .unwrap_or("")
+        .to_string();
+    if !hypervisor_config_path.is_empty() && hypervisor_name.is_empty() {
+        return Err(anyhow!("a hypervisor configuration requires a test vm"));
+    }
+
+    let prepare_only = args.contains_id("prepare-only");
+    if prepare_only && hypervisor_name.is_empty() {
+        return Err(anyhow!("prepare only requires a test vm"));
+    }
+
+    let dump_config = args.contains_id("dump-config");
+    if dump_config && hypervisor_name.is_empty() {
+        return Err(anyhow!("dumping the configuration requires a test vm"));
+    }
+
     let mut commands: Vec<&str> = Vec::new();
 
-    if !interactive {
+    if !interactive && !prepare_only && !dump_config {
         commands = args
             .get_many::<String>("cmd")
             .ok_or_else(|| anyhow!("need commands to send to the server"))?
@@ -196,6 +348,267 @@ fn connect(name: &str, global_args: clap::ArgMatches) -> Result<()> {
     let hybrid_vsock = args.contains_id("hybrid-vsock");
     let no_auto_values = args.contains_id("no-auto-values");
 
+    let io_threads = match args.get_one::<String>("io-threads") {
+        Some(n) => n
+            .parse::<u32>()
+            .map_err(|e| anyhow!("io threads must be an integer: {:?}", e))?,
+        None => 0,
+    };
+
+    let memory_limit = parse_resource_limit(args, "memory-limit")?;
+    let cpu_quota = parse_resource_limit(args, "cpu-quota")?;
+
+    let resolv_conf = get_guest_etc_file(args, "resolv-conf")?;
+    let hosts_file = get_guest_etc_file(args, "hosts-file")?;
+
+    let state_file = match args.get_one::<String>("session") {
+        Some(name) => {
+            if hypervisor_name.is_empty() {
+                return Err(anyhow!("a session requires a test vm"));
+            }
+            vm::session_state_file(name, true)?
+        }
+        None => args
+            .get_one::<String>("state-file")
+            .map(|s| s.as_str())
+            .unwrap_or("")
+            .to_string(),
+    };
+
+    let agent_version = args
+        .get_one::<String>("agent-version")
+        .map(|s| s.as_str())
+        .unwrap_or("")
+        .to_string();
+
+    let guest_logs_file = args
+        .get_one::<String>("collect-guest-logs")
+        .map(|s| s.as_str())
+        .unwrap_or("")
+        .to_string();
+
+    let rootfs_dir = args
+        .get_one::<String>("rootfs-dir")
+        .map(|s| s.as_str())
+        .unwrap_or("")
+        .to_string();
+
+    let scratch_disks = parse_scratch_disks(args)?;
+
+    let iso_images: Vec<String> = args
+        .get_many::<String>("iso")
+        .map(|images| images.cloned().collect())
+        .unwrap_or_default();
+
+    let pmem_image = match args.get_one::<String>("pmem") {
+        Some(spec) => {
+            let (path, fstype) = spec
+                .split_once(':')
+                .filter(|(path, fstype)| !path.is_empty() && !fstype.is_empty())
+                .ok_or_else(|| anyhow!("invalid pmem image {:?}: expected FILE:FSTYPE", spec))?;
+
+            Some(vm::PmemImageConfig {
+                path: path.to_string(),
+                fstype: fstype.to_string(),
+            })
+        }
+        None => None,
+    };
+
+    let tap_device = args
+        .get_one::<String>("tap")
+        .map(|s| s.to_string())
+        .unwrap_or_default();
+    if !tap_device.is_empty() && hypervisor_name.is_empty() {
+        return Err(anyhow!("a tap device requires a test vm"));
+    }
+
+    let vfio_devices: Vec<String> = args
+        .get_many::<String>("vfio")
+        .map(|devices| devices.cloned().collect())
+        .unwrap_or_default();
+    if !vfio_devices.is_empty() && hypervisor_name.is_empty() {
+        return Err(anyhow!("vfio devices require a test vm"));
+    }
+    for bdf in &vfio_devices {
+        vm::vm_utils::parse_pci_bdf(bdf)?;
+    }
+
+    let virtiofs_shares = parse_virtiofs_shares(args)?;
+    if !virtiofs_shares.is_empty() && hypervisor_name.is_empty() {
+        return Err(anyhow!("virtio-fs shares require a test vm"));
+    }
+
+    let virtio_fs_cache = match args.get_one::<String>("virtio-fs-cache") {
+        Some(mode) => {
+            // none is the deprecated name of never
+            let mode = match mode.as_str() {
+                "none" => "never",
+                mode => mode,
+            };
+            if !VIRTIO_FS_CACHE_MODES.contains(&mode) {
+                return Err(anyhow!(
+                    "invalid virtio-fs cache mode {:?}: expected one of {}",
+                    mode,
+                    VIRTIO_FS_CACHE_MODES.join(", ")
+                ));
+            }
+            if hypervisor_name.is_empty() {
+                return Err(anyhow!("a virtio-fs cache mode requires a test vm"));
+            }
+            Some(mode.to_string())
+        }
+        None => None,
+    };
+
+    let virtio_fs_dax_window = match args.get_one::<String>("virtio-fs-dax") {
+        Some(size) => {
+            let window = utils::parse_byte_size(size)?;
+            if window == 0 || window % (1 << 20) != 0 {
+                return Err(anyhow!(
+                    "virtio-fs DAX window {:?} must be a non zero number of MiB",
+                    size
+                ));
+            }
+            if hypervisor_name.is_empty() {
+                return Err(anyhow!("a virtio-fs DAX window requires a test vm"));
+            }
+            Some(window)
+        }
+        None => None,
+    };
+
+    let strict_source_fs = args.contains_id("strict-source-fs");
+
+    let guest_base_path = match args.get_one::<String>("guest-base-path") {
+        Some(path) => {
+            if !Path::new(path).is_absolute() || path == "/" {
+                return Err(anyhow!(
+                    "guest base path must be an absolute directory: {:?}",
+                    path
+                ));
+            }
+            if hypervisor_name.is_empty() {
+                return Err(anyhow!("a guest base path requires a test vm"));
+            }
+            path.to_string()
+        }
+        None => String::new(),
+    };
+    // the guest paths of the storages below depend on it
+    vm::vm_utils::set_guest_base_path(&guest_base_path);
+
+    // the tmpfs storages need no device, unlike those of the test vm
+    let disk_storages = parse_tmpfs_volumes(args)?
+        .iter()
+        .enumerate()
+        .map(|(index, tmpfs)| vm::vm_utils::get_tmpfs_storage(tmpfs, index))
+        .collect();
+
+    let print_cmdline = args.contains_id("print-cmdline");
+
+    let idle_timeout = match args.get_one::<String>("idle-timeout") {
+        Some(secs) => {
+            let secs = secs
+                .parse::<u64>()
+                .map_err(|e| anyhow!("idle timeout must be a number of seconds: {:?}", e))?;
+            if !interactive {
+                return Err(anyhow!(
+                    "idle timeout is only supported in interactive mode"
+                ));
+            }
+            Some(Duration::from_secs(secs))
+        }
+        None => None,
+    };
+
+    let attach_jobs = match args.get_one::<String>("attach-jobs") {
+        Some(n) => {
+            let jobs = n
+                .parse::<usize>()
+                .map_err(|e| anyhow!("attach jobs must be an integer: {:?}", e))?;
+            if jobs == 0 {
+                return Err(anyhow!("attach jobs must be at least 1"));
+            }
+            if hypervisor_name.is_empty() {
+                return Err(anyhow!("attach jobs require a test vm"));
+            }
+            jobs
+        }
+        None => 0,
+    };
+
+    let boot_timeout = match args.get_one::<String>("boot-timeout") {
+        Some(t) => {
+            let timeout = Duration::from_nanos(utils::human_time_to_ns(t)? as u64);
+            if timeout.as_millis() == 0 {
+                return Err(anyhow!("boot timeout must be at least 1ms"));
+            }
+            if timeout.as_millis() > i32::MAX as u128 {
+                return Err(anyhow!("boot timeout {} is too large", t));
+            }
+            if hypervisor_name.is_empty() {
+                return Err(anyhow!("a boot timeout requires a test vm"));
+            }
+            Some(timeout)
+        }
+        None => None,
+    };
+
+    let pause_window = match args.get_one::<String>("pause-vm") {
+        Some(t) => {
+            if hypervisor_name.is_empty() {
+                return Err(anyhow!("pausing the vm requires a test vm"));
+            }
+            Some(Duration::from_nanos(utils::human_time_to_ns(t)? as u64))
+        }
+        None => None,
+    };
+
+    let resize_vcpus = parse_resize_target(args, "resize-vcpus", &hypervisor_name)?;
+    let resize_memory_mb = parse_resize_target(args, "resize-memory", &hypervisor_name)?;
+
+    let stop_timeout = match args.get_one::<String>("stop-timeout") {
+        Some(secs) => Some(Duration::from_secs(secs.parse::<u64>().map_err(|e| {
+            anyhow!("stop timeout must be a number of seconds: {:?}", e)
+        })?)),
+        None => None,
+    };
+
+    let stop_kill = args.contains_id("stop-kill");
+    if stop_kill && stop_timeout.is_none() {
+        return Err(anyhow!("stop kill requires a stop timeout"));
+    }
+
+    let keep_without_agent = args.contains_id("keep-vm-without-agent");
+    if keep_without_agent && state_file.is_empty() {
+        return Err(anyhow!(
+            "keeping the vm without agent requires a session or state file"
+        ));
+    }
+
+    let mem_prealloc = args.contains_id("mem-prealloc");
+
+    let hugepages = args.contains_id("hugepages");
+    if hugepages && hypervisor_name.is_empty() {
+        return Err(anyhow!("hugepages require a test vm"));
+    }
+    let mem_shared = args.get_one::<bool>("mem-shared").copied();
+
+    let trace_rpc = args.contains_id("trace-rpc");
+
+    let trace_rpc_redact = match args.get_one::<String>("trace-rpc-redact") {
+        Some(n) => n
+            .parse::<usize>()
+            .map_err(|e| anyhow!("trace rpc redact size must be an integer: {:?}", e))?,
+        None => 0,
+    };
+
+    let cleanup_temp = args
+        .get_one::<bool>("cleanup-temp")
+        .copied()
+        .unwrap_or(true);
+
     let mut cfg = Config {
         server_address,
         bundle_dir,
@@ -206,7 +619,47 @@ fn connect(name: &str, global_args: clap::ArgMatches) -> Result<()> {
         ignore_errors,
         no_auto_values,
         hypervisor_name,
+        hypervisor_config_path,
         shared_fs_host_path: String::new(),
+        shared_fs_backend: String::new(),
+        cleanup_temp,
+        json_output: is_json_output(&global_args),
+        io_threads,
+        memory_limit,
+        cpu_quota,
+        resolv_conf,
+        hosts_file,
+        state_file,
+        trace_rpc,
+        trace_rpc_redact,
+        agent_version,
+        guest_logs_file,
+        rootfs_dir,
+        scratch_disks,
+        attach_jobs,
+        iso_images,
+        pmem_image,
+        tap_device,
+        vfio_devices,
+        virtiofs_shares,
+        virtio_fs_cache,
+        virtio_fs_dax_window,
+        strict_source_fs,
+        print_cmdline,
+        mem_prealloc,
+        hugepages,
+        mem_shared,
+        idle_timeout,
+        boot_timeout,
+        pause_window,
+        resize_vcpus,
+        resize_memory_mb,
+        stop_timeout,
+        stop_kill,
+        keep_without_agent,
+        prepare_only,
+        dump_config,
+        disk_storages,
     };
 
     let result = rpc::run(&logger, &mut cfg, commands);
@@ -214,6 +667,322 @@ fn connect(name: &str, global_args: clap::ArgMatches) -> Result<()> {
     result.map_err(|e| anyhow!(e))
 }
 
+// Parse an optional, non-negative container resource limit. Zero means the
+// limit is not set.
+fn parse_resource_limit(args: &clap::ArgMatches, name: &str) -> Result<i64> {
+    let limit = match args.get_one::<String>(name) {
+        Some(v) => v
+            .parse::<i64>()
+            .map_err(|e| anyhow!("{} must be an integer: {:?}", name, e))?,
+        None => 0,
+    };
+
+    if limit < 0 {
+        return Err(anyhow!("{} must not be negative", name));
+    }
+
+    Ok(limit)
+}
+
+// Parse the optional, non-zero amount of a test vm resource to resize it to
+// once booted
+fn parse_resize_target(
+    args: &clap::ArgMatches,
+    name: &str,
+    hypervisor_name: &str,
+) -> Result<Option<u32>> {
+    let target = match args.get_one::<String>(name) {
+        Some(v) => v
+            .parse::<u32>()
+            .map_err(|e| anyhow!("{} must be an integer: {:?}", name, e))?,
+        None => return Ok(None),
+    };
+
+    if target == 0 {
+        return Err(anyhow!("{} must be at least 1", name));
+    }
+    if hypervisor_name.is_empty() {
+        return Err(anyhow!("{} requires a test vm", name));
+    }
+
+    Ok(Some(target))
+}
+
+// Parse the scratch disks, each one given as
+// SIZE_MB:FSTYPE[:ro][:block-driver=DRIVER]
+fn parse_scratch_disks(args: &clap::ArgMatches) -> Result<Vec<vm::ScratchDiskConfig>> {
+    let specs = match args.get_many::<String>("scratch-disk") {
+        Some(specs) => specs,
+        None => return Ok(Vec::new()),
+    };
+
+    specs
+        .map(|spec| {
+            let (size, fstype) = spec.split_once(':').ok_or_else(|| {
+                anyhow!(
+                    "invalid scratch disk {:?}: expected SIZE_MB:FSTYPE[:ro][:block-driver=DRIVER]",
+                    spec
+                )
+            })?;
+
+            let mut options = fstype.split(':');
+            let fstype = options.next().unwrap_or_default();
+
+            let mut readonly = false;
+            let mut block_driver = None;
+            for option in options {
+                match option.split_once('=') {
+                    None if option == "ro" => readonly = true,
+                    Some(("block-driver", driver)) if !driver.is_empty() => {
+                        block_driver = Some(driver.to_string())
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "invalid scratch disk option {:?} in {:?}",
+                            option,
+                            spec
+                        ))
+                    }
+                }
+            }
+
+            let size_mb = size
+                .parse::<u64>()
+                .map_err(|e| anyhow!("invalid scratch disk size {:?}: {:?}", size, e))?;
+
+            if size_mb == 0 || fstype.is_empty() {
+                return Err(anyhow!("invalid scratch disk {:?}", spec));
+            }
+
+            Ok(vm::ScratchDiskConfig {
+                size_mb,
+                fstype: fstype.to_string(),
+                readonly,
+                block_driver,
+            })
+        })
+        .collect()
+}
+
+// Parse the tmpfs storages requested as SIZE[:MODE]
+fn parse_tmpfs_volumes(args: &clap::ArgMatches) -> Result<Vec<vm::TmpfsConfig>> {
+    let specs = match args.get_many::<String>("tmpfs") {
+        Some(specs) => specs,
+        None => return Ok(Vec::new()),
+    };
+
+    specs
+        .map(|spec| {
+            let (size, mode) = match spec.split_once(':') {
+                Some((size, mode)) => (size, Some(mode)),
+                None => (spec.as_str(), None),
+            };
+
+            let size = utils::parse_byte_size(size)?;
+            if size == 0 {
+                return Err(anyhow!("invalid tmpfs {:?}: size must not be zero", spec));
+            }
+
+            let mode = match mode {
+                Some(mode) => Some(
+                    u32::from_str_radix(mode, 8)
+                        .ok()
+                        .filter(|mode| *mode <= 0o7777)
+                        .ok_or_else(|| anyhow!("invalid tmpfs mode in {:?}", spec))?,
+                ),
+                None => None,
+            };
+
+            Ok(vm::TmpfsConfig { size, mode })
+        })
+        .collect()
+}
+
+// Parse the extra virtio-fs shares requested as HOST_DIR:TAG:GUEST_PATH
+fn parse_virtiofs_shares(args: &clap::ArgMatches) -> Result<Vec<vm::VirtioFsShareConfig>> {
+    let specs = match args.get_many::<String>("virtiofs-share") {
+        Some(specs) => specs,
+        None => return Ok(Vec::new()),
+    };
+
+    specs
+        .map(|spec| {
+            let fields: Vec<&str> = spec.splitn(3, ':').collect();
+            if fields.len() != 3 || fields.iter().any(|f| f.is_empty()) {
+                return Err(anyhow!(
+                    "invalid virtio-fs share {:?}: expected HOST_DIR:TAG:GUEST_PATH",
+                    spec
+                ));
+            }
+
+            Ok(vm::VirtioFsShareConfig {
+                host_path: fields[0].to_string(),
+                tag: fields[1].to_string(),
+                guest_path: fields[2].to_string(),
+            })
+        })
+        .collect()
+}
+
+// Get the path of an optional host file to be provided to the guest
+// containers, checking that it is a readable file.
+fn get_guest_etc_file(args: &clap::ArgMatches, name: &str) -> Result<String> {
+    let path = match args.get_one::<String>(name) {
+        Some(p) => p.to_string(),
+        None => return Ok(String::new()),
+    };
+
+    let file = std::fs::File::open(&path).map_err(|e| anyhow!("{} {:?}: {}", name, path, e))?;
+
+    let metadata = file
+        .metadata()
+        .map_err(|e| anyhow!("{} {:?}: {}", name, path, e))?;
+
+    if !metadata.is_file() {
+        return Err(anyhow!("{} {:?} is not a file", name, path));
+    }
+
+    Ok(path)
+}
+
+fn is_json_output(global_args: &clap::ArgMatches) -> bool {
+    global_args
+        .get_one::<String>("output")
+        .map(|s| s == OUTPUT_FORMAT_JSON)
+        .unwrap_or_default()
+}
+
+fn binary_versions(global_args: clap::ArgMatches) -> Result<()> {
+    let args = global_args
+        .subcommand_matches("binary-versions")
+        .ok_or_else(|| anyhow!("BUG: missing sub-command arguments"))?;
+
+    let hypervisor_name = args
+        .get_one::<String>("vm")
+        .map(|s| s.as_str())
+        .ok_or_else(|| anyhow!("need hypervisor name"))?;
+
+    let config_path = args
+        .get_one::<String>("hypervisor-config")
+        .map(|s| s.as_str());
+
+    let versions = vm::binary_versions(hypervisor_name, config_path)?;
+
+    if is_json_output(&global_args) {
+        let probes: Vec<ProbeResult> = versions
+            .into_iter()
+            .map(|v| ProbeResult::BinaryVersion {
+                name: v.name,
+                path: v.path,
+                version: v.version,
+            })
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&probes)?);
+    } else {
+        for v in versions {
+            println!("{} ({}): {}", v.name, v.path, v.version);
+        }
+    }
+
+    Ok(())
+}
+
+fn measure_boot(global_args: clap::ArgMatches) -> Result<()> {
+    let args = global_args
+        .subcommand_matches("measure-boot")
+        .ok_or_else(|| anyhow!("BUG: missing sub-command arguments"))?;
+
+    let hypervisor_name = args
+        .get_one::<String>("vm")
+        .map(|s| s.as_str())
+        .ok_or_else(|| anyhow!("need hypervisor name"))?;
+
+    let timeout = args
+        .get_one::<String>("timeout")
+        .map(|s| s.as_str())
+        .ok_or_else(|| anyhow!("need agent wait timeout"))?;
+    let timeout = Duration::from_nanos(utils::human_time_to_ns(timeout)? as u64);
+
+    let duration = client::measure_boot(hypervisor_name, timeout)?;
+
+    println!("boot time: {}ms", duration.as_millis());
+
+    Ok(())
+}
+
+fn teardown(global_args: clap::ArgMatches) -> Result<()> {
+    let args = global_args
+        .subcommand_matches("teardown")
+        .ok_or_else(|| anyhow!("BUG: missing sub-command arguments"))?;
+
+    let state_file = args
+        .get_one::<String>("state-file")
+        .map(|s| s.as_str())
+        .ok_or_else(|| anyhow!("need state file"))?;
+
+    vm::teardown_vm_state(state_file)
+}
+
+fn sessions(global_args: clap::ArgMatches) -> Result<()> {
+    let sessions = vm::list_sessions()?;
+
+    let is_running = |state: &vm::TestVmState| {
+        state.hypervisor_pid != 0 && !vm::vm_utils::process_exited(state.hypervisor_pid)
+    };
+
+    if is_json_output(&global_args) {
+        let probes: Vec<ProbeResult> = sessions
+            .into_iter()
+            .map(|(name, state)| ProbeResult::Session {
+                name,
+                running: is_running(&state),
+                hypervisor: state.hypervisor_name,
+                socket_addr: state.socket_addr,
+                hypervisor_pid: state.hypervisor_pid,
+                virtiofsd_pid: state.virtiofsd_pid,
+                shared_path: state.shared_path,
+            })
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&probes)?);
+    } else {
+        for (name, state) in sessions {
+            let status = if is_running(&state) {
+                "running"
+            } else {
+                "exited"
+            };
+
+            println!(
+                "{}: {} pid {} ({}), socket {}, virtiofsd pid {}, shared path {}",
+                name,
+                state.hypervisor_name,
+                state.hypervisor_pid,
+                status,
+                state.socket_addr,
+                state.virtiofsd_pid,
+                state.shared_path
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn kill_session(global_args: clap::ArgMatches) -> Result<()> {
+    let args = global_args
+        .subcommand_matches("kill-session")
+        .ok_or_else(|| anyhow!("BUG: missing sub-command arguments"))?;
+
+    let name = args
+        .get_one::<String>("session")
+        .map(|s| s.as_str())
+        .ok_or_else(|| anyhow!("need session name"))?;
+
+    vm::teardown_vm_state(&vm::session_state_file(name, false)?)
+}
+
 fn real_main() -> Result<()> {
     let name = crate_name!();
 
@@ -235,16 +1004,51 @@ fn real_main() -> Result<()> {
                 .value_parser(logging::get_log_levels())
                 .required(false),
         )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .help("output format of the probe results")
+                .default_value(OUTPUT_FORMAT_TEXT)
+                .value_parser([OUTPUT_FORMAT_TEXT, OUTPUT_FORMAT_JSON])
+                .required(false),
+        )
         .subcommand(
             Command::new("connect")
                 .about("Connect to agent")
                 .after_help(WARNING_TEXT)
+                .arg(
+                    Arg::new("agent-version")
+                    .long("agent-version")
+                    .help("Fail unless the agent version matches (exact version, or comparisons like \">=3.2.0, <4.0.0\")")
+                    .value_name("VERSION"),
+                    )
+                .arg(
+                    Arg::new("attach-jobs")
+                    .long("attach-jobs")
+                    .help("Number of scratch disk images created and formatted at once (default: 2) (requires --vm)")
+                    .value_name("N"),
+                    )
+                .arg(
+                    Arg::new("boot-timeout")
+                    .long("boot-timeout")
+                    .help("Maximum time for the hypervisor to start the test VM, and then for its agent to become ready, using human-readable suffixes (500ms, 30s, 2m, etc) (default: 10s) (requires --vm)")
+                    .value_name("human-time"),
+                    )
                 .arg(
                     Arg::new("bundle-dir")
                     .long("bundle-dir")
                     .help("OCI bundle directory")
                     .value_name("directory"),
                     )
+                .arg(
+                    Arg::new("cleanup-temp")
+                    .long("cleanup-temp")
+                    .help("Remove the temporary files created for the test VM on exit")
+                    .value_parser(clap::value_parser!(bool))
+                    .default_value("true")
+                    .value_name("BOOL"),
+                    )
                 .arg(
                     Arg::new("cmd")
                     .long("cmd")
@@ -252,11 +1056,45 @@ fn real_main() -> Result<()> {
                     .num_args(0..)
                     .help("API command (with optional arguments) to send to the server"),
                     )
+                .arg(
+                    Arg::new("collect-guest-logs")
+                    .long("collect-guest-logs")
+                    .help("Write the guest logs to the file before disconnecting")
+                    .value_name("FILE"),
+                    )
+                .arg(
+                    Arg::new("cpu-quota")
+                    .long("cpu-quota")
+                    .help("CPU quota (in usecs per 100ms period) of the created containers")
+                    .value_name("USECS"),
+                    )
+                .arg(
+                    Arg::new("dump-config")
+                    .long("dump-config")
+                    .help("Print the effective configuration the test VM would boot with, the VM options applied, and exit without booting it (requires --vm)"),
+                    )
+                .arg(
+                    Arg::new("guest-base-path")
+                    .long("guest-base-path")
+                    .help("Guest directory the agent is configured to place the storages and shares under (default: /run/kata-containers) (requires --vm)")
+                    .value_name("PATH"),
+                    )
                 .arg(
                     Arg::new("ignore-errors")
                     .long("ignore-errors")
                     .help("Don't exit on first error"),
                     )
+                .arg(
+                    Arg::new("hosts-file")
+                    .long("hosts-file")
+                    .help("File used as /etc/hosts in the created containers (only useful with --vm)")
+                    .value_name("FILE"),
+                    )
+                .arg(
+                    Arg::new("hugepages")
+                    .long("hugepages")
+                    .help("Back the test VM memory with hugepages of the configured type, checking the host has enough of them (requires --vm dragonball)"),
+                    )
                 .arg(
                     Arg::new("hybrid-vsock")
                     .long("hybrid-vsock")
@@ -269,30 +1107,210 @@ fn real_main() -> Result<()> {
                     .default_value(DEFAULT_KATA_AGENT_API_VSOCK_PORT)
                     .value_name("PORT")
                     )
+                .arg(
+                    Arg::new("hypervisor-config")
+                    .long("hypervisor-config")
+                    .help("Kata configuration file used instead of the hypervisor default one (only useful with --vm)")
+                    .value_name("FILE"),
+                    )
+                .arg(
+                    Arg::new("idle-timeout")
+                    .long("idle-timeout")
+                    .help("In interactive mode, shut down and tear the test VM down when no command is entered within the number of seconds")
+                    .value_name("SECONDS"),
+                    )
                 .arg(
                     Arg::new("interactive")
                     .short('i')
                     .long("interactive")
                     .help("Allow interactive client"),
                     )
+                .arg(
+                    Arg::new("io-threads")
+                    .long("io-threads")
+                    .help("Number of IO threads for the test VM block devices (only useful with --vm qemu)")
+                    .value_name("N"),
+                    )
+                .arg(
+                    Arg::new("iso")
+                    .long("iso")
+                    .help("Attach an ISO9660 image read-only to the test VM, mounted by the agent on sandbox creation (may be repeated)")
+                    .action(clap::ArgAction::Append)
+                    .value_name("FILE"),
+                    )
+                .arg(
+                    Arg::new("keep-vm-without-agent")
+                    .long("keep-vm-without-agent")
+                    .help("Leave the test VM running should the hypervisor report no agent socket, saving its state for inspection and teardown (requires --session or --state-file)"),
+                    )
+                .arg(
+                    Arg::new("mem-prealloc")
+                    .long("mem-prealloc")
+                    .help("Preallocate the test VM memory"),
+                    )
+                .arg(
+                    Arg::new("mem-shared")
+                    .long("mem-shared")
+                    .help("Share the test VM memory with the host, which virtio-fs requires (default: hypervisor decides)")
+                    .value_parser(clap::value_parser!(bool))
+                    .value_name("BOOL"),
+                    )
+                .arg(
+                    Arg::new("memory-limit")
+                    .long("memory-limit")
+                    .help("Memory limit (in bytes) of the created containers")
+                    .value_name("BYTES"),
+                    )
                 .arg(
                     Arg::new("no-auto-values")
                     .short('n')
                     .long("no-auto-values")
                     .help("Disable automatic generation of values for sandbox ID, container ID, etc"),
                     )
+                .arg(
+                    Arg::new("pause-vm")
+                    .long("pause-vm")
+                    .help("Pause the test VM for the given time using human-readable suffixes (500ms, 30s, 2m, etc) once booted, resuming it before running the commands (requires --vm)")
+                    .value_name("human-time"),
+                    )
+                .arg(
+                    Arg::new("pmem")
+                    .long("pmem")
+                    .help("Attach a filesystem image of the given type to the test VM as an nvdimm device, mounted by the agent on sandbox creation (only useful with --vm qemu)")
+                    .value_name("FILE:FSTYPE"),
+                    )
+                .arg(
+                    Arg::new("prepare-only")
+                    .long("prepare-only")
+                    .help("Validate the test VM boot up to starting the VM, tear it down and exit without running commands (requires --vm)"),
+                    )
+                .arg(
+                    Arg::new("print-cmdline")
+                    .long("print-cmdline")
+                    .help("Print the kernel command line of the test VM once booted"),
+                    )
+                .arg(
+                    Arg::new("resize-memory")
+                    .long("resize-memory")
+                    .help("Hotplug memory into the test VM once booted for it to have SIZE_MB megabytes in total, up to the configured maximum (requires --vm)")
+                    .value_name("SIZE_MB"),
+                    )
+                .arg(
+                    Arg::new("resize-vcpus")
+                    .long("resize-vcpus")
+                    .help("Hotplug or unplug vcpus of the test VM once booted for it to have N of them, up to the configured maximum (requires --vm)")
+                    .value_name("N"),
+                    )
+                .arg(
+                    Arg::new("resolv-conf")
+                    .long("resolv-conf")
+                    .help("File used as /etc/resolv.conf in the created containers (only useful with --vm)")
+                    .value_name("FILE"),
+                    )
+                .arg(
+                    Arg::new("rootfs-dir")
+                    .long("rootfs-dir")
+                    .help("Boot the test VM from a rootfs directory shared over virtio-fs (only useful with --vm qemu)")
+                    .value_name("DIRECTORY"),
+                    )
+                .arg(
+                    Arg::new("scratch-disk")
+                    .long("scratch-disk")
+                    .help("Attach a freshly formatted disk of SIZE_MB megabytes to the test VM, read-only with :ro and with another block driver than the configured one with :block-driver= (virtio-blk-pci, virtio-blk-mmio, virtio-blk-ccw or virtio-scsi, as supported by the hypervisor), mounted by the agent on sandbox creation (may be repeated)")
+                    .action(clap::ArgAction::Append)
+                    .value_name("SIZE_MB:FSTYPE[:ro][:block-driver=DRIVER]"),
+                    )
                 .arg(
                     Arg::new("server-address")
                     .long("server-address")
                     .help("server URI (vsock:// or unix://)")
                     .value_name("URI"),
                     )
+                .arg(
+                    Arg::new("session")
+                    .long("session")
+                    .help("Name the test VM session, saving its state so it can be listed with sessions and torn down with kill-session (requires --vm)")
+                    .conflicts_with("state-file")
+                    .value_name("NAME"),
+                    )
+                .arg(
+                    Arg::new("state-file")
+                    .long("state-file")
+                    .help("Save the test VM state to the file, for use with the teardown sub-command")
+                    .value_name("FILE"),
+                    )
+                .arg(
+                    Arg::new("stop-kill")
+                    .long("stop-kill")
+                    .help("Kill the test VM hypervisor if it has not exited once the stop timeout expired (requires --stop-timeout)"),
+                    )
+                .arg(
+                    Arg::new("stop-timeout")
+                    .long("stop-timeout")
+                    .help("Wait up to the number of seconds for the test VM hypervisor to exit after stopping the VM")
+                    .value_name("SECONDS"),
+                    )
+                .arg(
+                    Arg::new("strict-source-fs")
+                    .long("strict-source-fs")
+                    .help("Fail instead of warning when a storage source is on a network or overlay host filesystem"),
+                    )
+                .arg(
+                    Arg::new("tap")
+                    .long("tap")
+                    .help("Attach a network device backed by the named tap device to the test VM, creating the tap if missing and removing it with the VM (only useful with --vm qemu or cloud-hypervisor)")
+                    .value_name("NAME"),
+                    )
                 .arg(
                     Arg::new("timeout")
                     .long("timeout")
                     .help("timeout value as nanoseconds or using human-readable suffixes (0 [forever], 99ns, 30us, 2ms, 5s, 7m, etc)")
                     .value_name("human-time"),
                     )
+                .arg(
+                    Arg::new("tmpfs")
+                    .long("tmpfs")
+                    .help("Mount a tmpfs of SIZE bytes, with an optional k, m or g suffix, and octal MODE in the guest as an ephemeral storage on sandbox creation (may be repeated)")
+                    .action(clap::ArgAction::Append)
+                    .value_name("SIZE[:MODE]"),
+                    )
+                .arg(
+                    Arg::new("trace-rpc")
+                    .long("trace-rpc")
+                    .help("Log the content of every agent API request and response"),
+                    )
+                .arg(
+                    Arg::new("trace-rpc-redact")
+                    .long("trace-rpc-redact")
+                    .help("Redact the binary fields larger than this size when tracing API calls (only useful with --trace-rpc)")
+                    .value_name("BYTES"),
+                    )
+                .arg(
+                    Arg::new("vfio")
+                    .long("vfio")
+                    .help("Pass the host PCI device at the [DDDD:]BB:DD.F address, bound to vfio-pci, through to the test VM (may be repeated) (only useful with --vm qemu or cloud-hypervisor)")
+                    .action(clap::ArgAction::Append)
+                    .value_name("BDF"),
+                    )
+                .arg(
+                    Arg::new("virtio-fs-cache")
+                    .long("virtio-fs-cache")
+                    .help("Cache mode of the test VM virtio-fs shares: never, auto or always (requires --vm)")
+                    .value_name("MODE"),
+                    )
+                .arg(
+                    Arg::new("virtio-fs-dax")
+                    .long("virtio-fs-dax")
+                    .help("Enable virtio-fs DAX with a window of SIZE bytes, with an optional k, m or g suffix, a whole number of MiB (only useful with --vm dragonball)")
+                    .value_name("SIZE"),
+                    )
+                .arg(
+                    Arg::new("virtiofs-share")
+                    .long("virtiofs-share")
+                    .help("Share the host directory with the test VM over a virtio-fs device of its own under the mount tag, mounted by the agent at the guest path on sandbox creation (may be repeated) (only useful with --vm qemu or cloud-hypervisor)")
+                    .action(clap::ArgAction::Append)
+                    .value_name("HOST_DIR:TAG:GUEST_PATH"),
+                    )
                 .arg(
                     Arg::new("vm")
                     .long("vm")
@@ -300,6 +1318,65 @@ fn real_main() -> Result<()> {
                     .value_name("HYPERVISOR"),
                     )
                 )
+                .subcommand(
+                    Command::new("binary-versions")
+                    .about("Show versions of the configured hypervisor and virtiofsd binaries")
+                    .arg(
+                        Arg::new("hypervisor-config")
+                        .long("hypervisor-config")
+                        .help("Kata configuration file used instead of the hypervisor default one")
+                        .value_name("FILE"),
+                        )
+                    .arg(
+                        Arg::new("vm")
+                        .long("vm")
+                        .help("hypervisor whose configuration is used")
+                        .value_name("HYPERVISOR")
+                        .required(true),
+                        )
+                )
+                .subcommand(
+                    Command::new("measure-boot")
+                    .about("Measure the time from booting a test VM to the agent answering")
+                    .arg(
+                        Arg::new("timeout")
+                        .long("timeout")
+                        .help("maximum time to wait for the agent using human-readable suffixes (500ms, 30s, 2m, etc)")
+                        .default_value(DEFAULT_AGENT_WAIT_TIMEOUT)
+                        .value_name("human-time"),
+                        )
+                    .arg(
+                        Arg::new("vm")
+                        .long("vm")
+                        .help("hypervisor used to boot the test vm")
+                        .value_name("HYPERVISOR")
+                        .required(true),
+                        )
+                )
+                .subcommand(
+                    Command::new("teardown")
+                    .about("Tear down a test VM from the state file saved by connect --state-file")
+                    .arg(
+                        Arg::new("state-file")
+                        .help("test VM state file")
+                        .value_name("STATE-FILE")
+                        .required(true),
+                        )
+                )
+                .subcommand(
+                    Command::new("sessions")
+                    .about("List the test VM sessions started by connect --session")
+                )
+                .subcommand(
+                    Command::new("kill-session")
+                    .about("Tear down the test VM of a session started by connect --session")
+                    .arg(
+                        Arg::new("session")
+                        .help("session name")
+                        .value_name("NAME")
+                        .required(true),
+                        )
+                )
                 .subcommand(
                     Command::new("generate-cid")
                     .about("Create a random container ID")
@@ -332,6 +1409,11 @@ fn real_main() -> Result<()> {
             println!("{}", make_examples_text(name));
             Ok(())
         }
+        "binary-versions" => binary_versions(args),
+        "measure-boot" => measure_boot(args),
+        "teardown" => teardown(args),
+        "sessions" => sessions(args),
+        "kill-session" => kill_session(args),
         "connect" => connect(name, args),
         _ => Err(anyhow!(format!("invalid sub-command: {:?}", subcmd))),
     }
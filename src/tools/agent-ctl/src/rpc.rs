@@ -10,10 +10,26 @@ use slog::{o, Logger};
 
 use crate::client::client;
 use crate::types::Config;
+use crate::utils;
+use crate::vm;
 
 pub fn run(logger: &Logger, cfg: &mut Config, commands: Vec<&str>) -> Result<()> {
     // Maintain the global logger for the duration of the ttRPC comms
     let _guard = slog_scope::set_global_logger(logger.new(o!("subsystem" => "rpc")));
 
-    client(cfg, commands)
+    utils::set_rpc_tracing(cfg.trace_rpc, cfg.trace_rpc_redact);
+    vm::vm_utils::set_strict_source_fs(cfg.strict_source_fs);
+
+    let result = client(cfg, commands);
+
+    // in case the test vm could not release them
+    vm::vm_utils::detach_loop_devices();
+
+    // Emit whatever the probes found, even if a later command failed
+    if cfg.json_output {
+        let probes = utils::take_probe_results();
+        println!("{}", serde_json::to_string_pretty(&probes)?);
+    }
+
+    result
 }
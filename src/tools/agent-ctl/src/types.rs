@@ -3,8 +3,11 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::vm::{PmemImageConfig, ScratchDiskConfig, VirtioFsShareConfig};
+use protocols::agent::Storage;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 
 // Type used to pass optional state between cooperating API calls.
 pub type Options = HashMap<String, String>;
@@ -20,7 +23,47 @@ pub struct Config {
     pub ignore_errors: bool,
     pub no_auto_values: bool,
     pub hypervisor_name: String,
+    pub hypervisor_config_path: String,
     pub shared_fs_host_path: String,
+    pub shared_fs_backend: String,
+    pub cleanup_temp: bool,
+    pub json_output: bool,
+    pub io_threads: u32,
+    pub memory_limit: i64,
+    pub cpu_quota: i64,
+    pub resolv_conf: String,
+    pub hosts_file: String,
+    pub state_file: String,
+    pub trace_rpc: bool,
+    pub trace_rpc_redact: usize,
+    pub agent_version: String,
+    pub guest_logs_file: String,
+    pub rootfs_dir: String,
+    pub scratch_disks: Vec<ScratchDiskConfig>,
+    pub attach_jobs: usize,
+    pub strict_source_fs: bool,
+    pub print_cmdline: bool,
+    pub mem_prealloc: bool,
+    pub hugepages: bool,
+    pub mem_shared: Option<bool>,
+    pub idle_timeout: Option<Duration>,
+    pub boot_timeout: Option<Duration>,
+    pub pause_window: Option<Duration>,
+    pub resize_vcpus: Option<u32>,
+    pub resize_memory_mb: Option<u32>,
+    pub stop_timeout: Option<Duration>,
+    pub stop_kill: bool,
+    pub keep_without_agent: bool,
+    pub prepare_only: bool,
+    pub dump_config: bool,
+    pub iso_images: Vec<String>,
+    pub pmem_image: Option<PmemImageConfig>,
+    pub tap_device: String,
+    pub vfio_devices: Vec<String>,
+    pub virtiofs_shares: Vec<VirtioFsShareConfig>,
+    pub virtio_fs_cache: Option<String>,
+    pub virtio_fs_dax_window: Option<u64>,
+    pub disk_storages: Vec<Storage>,
 }
 
 // CopyFile input struct
@@ -41,4 +84,79 @@ pub struct SetPolicyInput {
 pub struct CreateContainerInput {
     pub image: String,
     pub id: String,
+    #[serde(default)]
+    pub volumes: Vec<VolumeInput>,
+    // have the container process create a marker in its shared rootfs once
+    // started, see WaitContainerReady
+    #[serde(default)]
+    pub ready_marker: bool,
+    // share the container rootfs read-only
+    #[serde(default)]
+    pub readonly_rootfs: bool,
+}
+
+// Guest directory bind mounted in a container, typically the mount point
+// of a storage shared by several containers of the sandbox
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct VolumeInput {
+    pub source: String,
+    pub destination: String,
+    #[serde(default)]
+    pub readonly: bool,
+    // bind mount options, ex: ["nosuid", "noexec"]
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+// Result of a diagnostic probe. With `--output json`, the results of all
+// the probes run are emitted as a single JSON array.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "probe", rename_all = "kebab-case")]
+pub enum ProbeResult {
+    BinaryVersion {
+        name: String,
+        path: String,
+        version: String,
+    },
+    BootTimings {
+        hypervisor: String,
+        config_load_ms: u64,
+        prepare_ms: u64,
+        devices_ms: Vec<(String, u64)>,
+        share_fs_ms: u64,
+        start_ms: u64,
+    },
+    NegotiatedFeatures {
+        hypervisor: String,
+        block_driver: String,
+        rootfs_driver: String,
+        shared_fs: String,
+        vsock: String,
+        io_threads: u32,
+    },
+    MountPropagation {
+        container_id: String,
+        mount: String,
+        propagation: Vec<String>,
+    },
+    PciPath {
+        container_id: String,
+        pci_path: String,
+        sysfs_path: String,
+    },
+    ReadThroughput {
+        container_id: String,
+        path: String,
+        size_mb: u32,
+        mb_per_sec: f64,
+    },
+    Session {
+        name: String,
+        hypervisor: String,
+        socket_addr: String,
+        hypervisor_pid: u32,
+        virtiofsd_pid: u32,
+        shared_path: String,
+        running: bool,
+    },
 }
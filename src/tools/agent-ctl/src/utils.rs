@@ -9,6 +9,8 @@ use crate::vm::vm_utils;
 use anyhow::{anyhow, Result};
 use oci::{Root as ociRoot, Spec as ociSpec};
 use oci_spec::runtime as oci;
+use protobuf::reflect::{ReflectValueBox, ReflectValueRef, RuntimeFieldType, RuntimeType};
+use protobuf::MessageFull;
 use protocols::agent::{CopyFileRequest, CreateContainerRequest, SetPolicyRequest};
 use protocols::oci::{
     Mount as ttrpcMount, Process as ttrpcProcess, Root as ttrpcRoot, Spec as ttrpcSpec,
@@ -16,12 +18,13 @@ use protocols::oci::{
 use rand::RngExt;
 use safe_path::scoped_join;
 use serde::de::DeserializeOwned;
-use slog::{debug, warn};
+use slog::{debug, info, warn};
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::Read;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 // Length of a sandbox identifier
@@ -29,6 +32,9 @@ const SANDBOX_ID_LEN: u8 = 64;
 
 const FILE_URI: &str = "file://";
 
+// Request argument to read the JSON request from stdin
+const STDIN_REQUEST: &str = "-";
+
 // Length of the guests hostname
 const MIN_HOSTNAME_LEN: u8 = 8;
 
@@ -39,7 +45,46 @@ const CONFIG_FILE: &str = "config.json";
 const OCI_CONFIG_TEMPLATE: &str =
     "/opt/kata/share/defaults/kata-containers/agent-ctl/oci_config.json";
 
+// Default CFS period (in usecs) used along with a cpu quota
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+
+// Bind mount options accepted for the container volumes, by groups of
+// options conflicting with each other
+const MOUNT_OPTION_GROUPS: &[&[&str]] = &[
+    &["bind", "rbind"],
+    &["ro", "rw"],
+    &["suid", "nosuid"],
+    &["dev", "nodev"],
+    &["exec", "noexec"],
+    &["sync", "async"],
+    &["dirsync"],
+    &["atime", "noatime"],
+    &["diratime", "nodiratime"],
+    &["relatime", "norelatime"],
+    &["noatime", "relatime", "strictatime"],
+    &[
+        "private",
+        "rprivate",
+        "shared",
+        "rshared",
+        "slave",
+        "rslave",
+        "unbindable",
+        "runbindable",
+    ],
+];
+
+// Whether the agent RPC payloads are traced
+static TRACE_RPC: AtomicBool = AtomicBool::new(false);
+
+// Size above which the binary fields of the traced payloads are redacted,
+// zero to show them in full
+static TRACE_RPC_REDACT: AtomicUsize = AtomicUsize::new(0);
+
 lazy_static! {
+    // Results of the probes run during this invocation
+    static ref PROBE_RESULTS: Mutex<Vec<ProbeResult>> = Mutex::new(Vec::new());
+
     // Create a mutable hash map statically
     static ref SIGNALS: Arc<Mutex<HashMap<&'static str, u8>>> = {
 
@@ -114,6 +159,162 @@ pub fn signame_to_signum(name: &str) -> Result<u8> {
     }
 }
 
+// Record the result of a probe so it can be reported at exit.
+pub fn record_probe_result(result: ProbeResult) {
+    PROBE_RESULTS.lock().unwrap().push(result);
+}
+
+// Return (and forget) all the probe results recorded so far.
+pub fn take_probe_results() -> Vec<ProbeResult> {
+    PROBE_RESULTS.lock().unwrap().drain(..).collect()
+}
+
+// Enable the tracing of the agent RPC payloads
+pub fn set_rpc_tracing(enabled: bool, redact_bytes: usize) {
+    TRACE_RPC.store(enabled, Ordering::Relaxed);
+    TRACE_RPC_REDACT.store(redact_bytes, Ordering::Relaxed);
+}
+
+pub fn log_request<M: MessageFull>(req: &M) {
+    debug!(sl!(), "sending request"; "request" => format!("{:?}", req));
+
+    if TRACE_RPC.load(Ordering::Relaxed) {
+        info!(
+            sl!(),
+            "rpc request {}:\n{}",
+            M::descriptor().name(),
+            format_rpc_message(req)
+        );
+    }
+}
+
+pub fn log_response<M: MessageFull>(reply: &M) {
+    info!(sl!(), "response received";
+        "response" => format!("{:?}", reply));
+
+    if TRACE_RPC.load(Ordering::Relaxed) {
+        info!(
+            sl!(),
+            "rpc response {}:\n{}",
+            M::descriptor().name(),
+            format_rpc_message(reply)
+        );
+    }
+}
+
+// Pretty print a protobuf message, replacing the top level binary fields
+// larger than the redaction size (ex: CopyFile or WriteStdin data).
+fn format_rpc_message<M: MessageFull>(msg: &M) -> String {
+    let redact_bytes = TRACE_RPC_REDACT.load(Ordering::Relaxed);
+    if redact_bytes == 0 {
+        return protobuf::text_format::print_to_string_pretty(msg);
+    }
+
+    let mut msg = msg.clone();
+
+    for field in M::descriptor().fields() {
+        if !matches!(
+            field.runtime_field_type(),
+            RuntimeFieldType::Singular(RuntimeType::VecU8)
+        ) {
+            continue;
+        }
+
+        let len = match field.get_singular_field_or_default(&msg) {
+            ReflectValueRef::Bytes(b) => b.len(),
+            _ => 0,
+        };
+
+        if len > redact_bytes {
+            let redacted = format!("<{len} bytes redacted>").into_bytes();
+            field.set_singular_field(&mut msg, ReflectValueBox::Bytes(redacted));
+        }
+    }
+
+    protobuf::text_format::print_to_string_pretty(&msg)
+}
+
+// Check a version against a requirement, which is either an exact version
+// or a comma separated list of comparisons using =, >, >=, < or <=
+// (ex: ">=3.2.0, <4.0.0"). Comparisons ignore any pre-release or build
+// suffix of the version.
+pub fn version_matches(version: &str, requirement: &str) -> Result<bool> {
+    let requirement = requirement.trim();
+
+    if !requirement.starts_with(['=', '<', '>']) {
+        return Ok(version.trim() == requirement);
+    }
+
+    let actual = parse_semver(version)?;
+
+    for comparison in requirement.split(',').map(|c| c.trim()) {
+        let (op, wanted) = match comparison.find(|c: char| !"=<>".contains(c)) {
+            Some(i) => comparison.split_at(i),
+            None => return Err(anyhow!("invalid version comparison {:?}", comparison)),
+        };
+
+        let wanted = parse_semver(wanted)?;
+
+        let matched = match op {
+            "=" => actual == wanted,
+            ">" => actual > wanted,
+            ">=" => actual >= wanted,
+            "<" => actual < wanted,
+            "<=" => actual <= wanted,
+            _ => return Err(anyhow!("invalid version operator {:?}", op)),
+        };
+
+        if !matched {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// Parse a "[v]MAJOR[.MINOR[.PATCH]][-pre][+build]" version, the missing
+// parts defaulting to zero.
+fn parse_semver(version: &str) -> Result<(u64, u64, u64)> {
+    let core = version
+        .trim()
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default();
+
+    let mut parts = [0u64; 3];
+    for (i, part) in core.split('.').enumerate() {
+        if i >= parts.len() {
+            return Err(anyhow!("invalid version {:?}", version));
+        }
+
+        parts[i] = part
+            .parse::<u64>()
+            .map_err(|e| anyhow!("invalid version {:?}: {}", version, e))?;
+    }
+
+    Ok((parts[0], parts[1], parts[2]))
+}
+
+// Convert a byte size, with an optional k, m or g binary suffix (like
+// "64m"), into the equivalent number of bytes.
+pub fn parse_byte_size(size: &str) -> Result<u64> {
+    let (digits, shift) = match size.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&size[..size.len() - 1], 10),
+        Some('m') => (&size[..size.len() - 1], 20),
+        Some('g') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+
+    let value = digits
+        .parse::<u64>()
+        .map_err(|e| anyhow!("invalid byte size {:?}: {:?}", size, e))?;
+
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("byte size {:?} is too large", size))
+}
+
 // Convert a human time fornat (like "2s") into the equivalent number
 // of nano seconds.
 pub fn human_time_to_ns(human_time: &str) -> Result<i64> {
@@ -212,6 +413,95 @@ pub fn get_option(name: &str, options: &mut Options, args: &str) -> Result<Strin
     Ok(value)
 }
 
+// Return the propagation tags ("shared", "slave", "propagate_from",
+// "unbindable") of the topmost mount at mount_point found in the given
+// /proc/self/mountinfo content. A mount without any tag is private, and
+// the "master" tag of a slave mount is reported as "slave", the name the
+// propagation is set with.
+pub fn get_mount_propagation(mountinfo: &str, mount_point: &str) -> Option<Vec<String>> {
+    // Format: id parent-id major:minor root mount-point mount-options [tag...] - fstype ...
+    for line in mountinfo.lines().rev() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if fields.len() < 7 || vm_utils::unescape_mount_path(fields[4]) != mount_point {
+            continue;
+        }
+
+        let mut tags: Vec<String> = fields[6..]
+            .iter()
+            .take_while(|f| **f != "-")
+            .map(|f| match f.split(':').next().unwrap_or_default() {
+                "master" => "slave".to_string(),
+                tag => tag.to_string(),
+            })
+            .collect();
+
+        if tags.is_empty() {
+            tags.push("private".to_string());
+        }
+
+        return Some(tags);
+    }
+
+    None
+}
+
+// Compute the throughput in MB/s from the summary line of dd, as printed
+// by both GNU coreutils and busybox:
+//   "104857600 bytes (105 MB, 100 MiB) copied, 0.25 s, 419 MB/s"
+//   "104857600 bytes (100.0MB) copied, 0.25s, 400.0MB/s"
+// It is computed from the byte count and elapsed time rather than taken
+// from the rate, whose unit varies.
+pub fn parse_dd_throughput(output: &str) -> Option<f64> {
+    let line = output.lines().rev().find(|l| l.contains(" copied"))?;
+    let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+
+    let bytes = fields
+        .first()?
+        .split_whitespace()
+        .next()?
+        .parse::<f64>()
+        .ok()?;
+
+    // the elapsed time follows the "copied" field
+    let copied = fields.iter().position(|f| f.ends_with("copied"))?;
+    let secs = fields
+        .get(copied + 1)?
+        .trim_end_matches('s')
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+
+    if secs <= 0.0 {
+        return None;
+    }
+
+    Some(bytes / secs / 1_000_000.0)
+}
+
+// Find the function 0 device sitting at the given PCI slot (hex, as in
+// a PCI path) in a listing of a guest sysfs PCI bus directory, whose
+// devices are named after their domain:bus:slot.function address.
+pub fn find_pci_slot_device(listing: &str, slot: &str) -> Option<String> {
+    let slot = u8::from_str_radix(slot, 16).ok()?;
+
+    listing
+        .split_whitespace()
+        .find(|entry| {
+            let fields: Vec<&str> = entry.split(':').collect();
+            match fields.as_slice() {
+                [domain, bus, dev_fn] if domain.len() == 4 && bus.len() == 2 => dev_fn
+                    .split_once('.')
+                    .map(|(dev, func)| {
+                        func == "0" && u8::from_str_radix(dev, 16).ok() == Some(slot)
+                    })
+                    .unwrap_or(false),
+                _ => false,
+            }
+        })
+        .map(|entry| entry.to_string())
+}
+
 pub fn generate_random_hex_string(len: u32) -> String {
     const CHARSET: &[u8] = b"abcdef0123456789";
     let mut rng = rand::rng();
@@ -426,19 +716,49 @@ pub fn str_to_bytes(s: &str) -> Result<Vec<u8>> {
 //
 //     let req: GetGuestDetailsRequest = make_request(args)?;
 //
+// - An inline JSON document:
+//
+//   As for the JSON URI, but without the scheme, for the string starting
+//   with '{'.
+//
+//   Example: '{"storages": [{"driver": "local", "mount_point": "/data"}]}'
+//
+// - Standard input:
+//
+//   A lone '-' reads the JSON document from stdin, which is only useful
+//   when the commands are not read interactively.
+//
+//   Example: 'CreateSandbox -'
+//
 pub fn make_request<T: Default + DeserializeOwned>(args: &str) -> Result<T> {
     if args.is_empty() {
         return Ok(Default::default());
     }
 
+    if args == STDIN_REQUEST {
+        let mut data = String::new();
+
+        std::io::stdin()
+            .read_to_string(&mut data)
+            .map_err(|e| anyhow!("failed to read the request from stdin: {}", e))?;
+
+        return parse_json_request(&data, "stdin");
+    }
+
+    if args.starts_with('{') {
+        return parse_json_request(args, "inline JSON");
+    }
+
     let (scheme, data) = split_uri(args)?;
 
     match scheme.as_str() {
-        "json" => Ok(serde_json::from_str(&data)?),
+        "json" => parse_json_request(&data, "json URI"),
         "file" => {
-            let file = File::open(data)?;
+            let file = File::open(&data)
+                .map_err(|e| anyhow!("cannot open request file {:?}: {}", data, e))?;
 
-            Ok(serde_json::from_reader(file)?)
+            serde_json::from_reader(file)
+                .map_err(|e| anyhow!("invalid JSON in request file {:?}: {}", data, e))
         }
         // Don't error since the args may contain key=value pairs which
         // are not handled by this functionz.
@@ -446,6 +766,10 @@ pub fn make_request<T: Default + DeserializeOwned>(args: &str) -> Result<T> {
     }
 }
 
+fn parse_json_request<T: DeserializeOwned>(data: &str, source: &str) -> Result<T> {
+    serde_json::from_str(data).map_err(|e| anyhow!("invalid JSON in {}: {}", source, e))
+}
+
 pub fn make_copy_file_request(input: &CopyFileInput) -> Result<CopyFileRequest> {
     // create dir mode permissions
     // Dir mode | 750
@@ -523,6 +847,7 @@ fn fix_oci_process_args(spec: &mut ttrpcSpec, bundle: &str) -> Result<()> {
 pub fn make_create_container_request(
     input: CreateContainerInput,
     shared_path: String,
+    shared_fs: &str,
 ) -> Result<CreateContainerRequest> {
     // read in the oci configuration template
     if !Path::new(OCI_CONFIG_TEMPLATE).exists() {
@@ -556,8 +881,10 @@ pub fn make_create_container_request(
                 sl!(),
                 "make_create_container_request: setting up fs sharing path"
             );
-            let share_bundle = vm_utils::share_rootfs(&image_bundle, &shared_path, &c_id)?;
-            req.mut_storages().push(vm_utils::get_virtiofs_storage());
+            let share_bundle =
+                vm_utils::share_rootfs(&image_bundle, &shared_path, &c_id, input.readonly_rootfs)?;
+            req.mut_storages()
+                .push(vm_utils::get_shared_fs_storage(shared_fs, None)?);
             share_bundle
         }
     };
@@ -568,6 +895,25 @@ pub fn make_create_container_request(
     // Fix the container process argument.
     fix_oci_process_args(&mut ttrpc_spec, &image_bundle)?;
 
+    add_volume_mounts(&mut ttrpc_spec, &input.volumes)?;
+
+    if input.readonly_rootfs {
+        ttrpc_spec.mut_Root().set_Readonly(true);
+    }
+
+    if input.ready_marker {
+        if shared_path.is_empty() {
+            return Err(anyhow!("a ready marker requires a test vm sharing files"));
+        }
+
+        if input.readonly_rootfs {
+            return Err(anyhow!("a ready marker requires a writable rootfs"));
+        }
+
+        let marker = vm_utils::reset_ready_marker(&shared_path, &c_id)?;
+        add_ready_marker(&mut ttrpc_spec, &marker);
+    }
+
     req.set_container_id(c_id);
     req.set_OCI(ttrpc_spec);
 
@@ -576,9 +922,349 @@ pub fn make_create_container_request(
     Ok(req)
 }
 
+// Set the memory and cpu cgroup limits in the OCI spec. A zero value
+// leaves the corresponding limit unset.
+pub fn set_resource_limits(spec: &mut ttrpcSpec, memory_limit: i64, cpu_quota: i64) {
+    if memory_limit == 0 && cpu_quota == 0 {
+        return;
+    }
+
+    let resources = spec.mut_Linux().mut_Resources();
+
+    if memory_limit > 0 {
+        resources.mut_Memory().set_Limit(memory_limit);
+    }
+
+    if cpu_quota > 0 {
+        let cpu = resources.mut_CPU();
+        cpu.set_Quota(cpu_quota);
+
+        // the quota is only meaningful against a period
+        if cpu.Period() == 0 {
+            cpu.set_Period(DEFAULT_CPU_PERIOD);
+        }
+    }
+}
+
+// Share the host files with the guest and bind mount them read-only in
+// the container. Each entry is the container path and the host source.
+pub fn share_etc_files(
+    spec: &mut ttrpcSpec,
+    shared_path: &str,
+    c_id: &str,
+    files: &[(&str, &str)],
+) -> Result<()> {
+    for (dest, src) in files {
+        let name = Path::new(dest)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("invalid container path {:?}", dest))?;
+
+        let guest_path = vm_utils::share_file(src, shared_path, c_id, name)?;
+
+        spec.Mounts.push(ttrpcMount {
+            destination: dest.to_string(),
+            source: guest_path,
+            type_: "bind".to_string(),
+            options: vec!["rbind".to_string(), "ro".to_string()],
+            ..Default::default()
+        });
+    }
+
+    Ok(())
+}
+
+// Run the container process through a shell creating the marker first, so
+// that the host can tell the container started from its shared rootfs
+fn add_ready_marker(spec: &mut ttrpcSpec, marker: &str) {
+    let process = spec.mut_Process();
+
+    let mut args = vec![
+        "/bin/sh".to_string(),
+        "-c".to_string(),
+        "touch \"$0\" && exec \"$@\"".to_string(),
+        marker.to_string(),
+    ];
+    args.extend(process.take_Args());
+
+    process.set_Args(args);
+}
+
+// Bind mount the guest directories in the container
+fn add_volume_mounts(spec: &mut ttrpcSpec, volumes: &[VolumeInput]) -> Result<()> {
+    for volume in volumes {
+        if !volume.source.starts_with('/') || !volume.destination.starts_with('/') {
+            return Err(anyhow!("volume paths must be absolute: {:?}", volume));
+        }
+
+        let mut options = volume.options.clone();
+        if volume.readonly {
+            options.push("ro".to_string());
+        }
+
+        let mut options = validate_mount_options(&options)
+            .map_err(|e| anyhow!("volume {}: {}", volume.destination, e))?;
+
+        // recursive read-write bind mount unless requested otherwise
+        if !options.iter().any(|o| o == "bind" || o == "rbind") {
+            options.insert(0, "rbind".to_string());
+        }
+        if !options.iter().any(|o| o == "ro" || o == "rw") {
+            options.push("rw".to_string());
+        }
+
+        spec.Mounts.push(ttrpcMount {
+            destination: volume.destination.clone(),
+            source: volume.source.clone(),
+            type_: "bind".to_string(),
+            options,
+            ..Default::default()
+        });
+    }
+
+    Ok(())
+}
+
+// Check the bind mount options are known and do not conflict with each
+// other, returning them without duplicates
+pub fn validate_mount_options(options: &[String]) -> Result<Vec<String>> {
+    let mut validated: Vec<String> = Vec::new();
+
+    for option in options {
+        if validated.contains(option) {
+            continue;
+        }
+
+        let groups: Vec<&[&str]> = MOUNT_OPTION_GROUPS
+            .iter()
+            .copied()
+            .filter(|group| group.contains(&option.as_str()))
+            .collect();
+        if groups.is_empty() {
+            return Err(anyhow!("unsupported mount option {:?}", option));
+        }
+
+        if let Some(other) = validated
+            .iter()
+            .find(|o| groups.iter().any(|group| group.contains(&o.as_str())))
+        {
+            return Err(anyhow!(
+                "mount option {:?} conflicts with {:?}",
+                option,
+                other
+            ));
+        }
+
+        validated.push(option.clone());
+    }
+
+    Ok(validated)
+}
+
 pub fn remove_container_image_mount(c_id: &str, share_fs: &str) -> Result<()> {
     if !share_fs.is_empty() {
         vm_utils::unshare_rootfs(share_fs, c_id)?;
     }
     image::remove_image_mount(c_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_matches() {
+        #[derive(Debug)]
+        struct TestData<'a> {
+            version: &'a str,
+            requirement: &'a str,
+            result: Result<bool>,
+        }
+
+        let tests = &[
+            // exact match
+            TestData {
+                version: "3.2.0",
+                requirement: "3.2.0",
+                result: Ok(true),
+            },
+            TestData {
+                version: "3.2.1",
+                requirement: " 3.2.0 ",
+                result: Ok(false),
+            },
+            // ranges
+            TestData {
+                version: "3.5.1",
+                requirement: ">=3.2.0, <4.0.0",
+                result: Ok(true),
+            },
+            TestData {
+                version: "3.2.0",
+                requirement: ">=3.2.0, <4.0.0",
+                result: Ok(true),
+            },
+            TestData {
+                version: "4.0.0",
+                requirement: ">=3.2.0, <4.0.0",
+                result: Ok(false),
+            },
+            TestData {
+                version: "3.1.9",
+                requirement: ">=3.2.0, <4.0.0",
+                result: Ok(false),
+            },
+            // v prefix
+            TestData {
+                version: "v3.2.0",
+                requirement: ">=3.2.0",
+                result: Ok(true),
+            },
+            TestData {
+                version: "3.2.0",
+                requirement: "=v3.2.0",
+                result: Ok(true),
+            },
+            // pre-release and build suffixes
+            TestData {
+                version: "3.2.0-rc1",
+                requirement: "=3.2.0",
+                result: Ok(true),
+            },
+            TestData {
+                version: "3.2.0+build.5",
+                requirement: "<=3.2.0",
+                result: Ok(true),
+            },
+            TestData {
+                version: "3.3.0-rc0+abc",
+                requirement: ">3.2.0",
+                result: Ok(true),
+            },
+            // missing minor and patch
+            TestData {
+                version: "3",
+                requirement: "=3.0.0",
+                result: Ok(true),
+            },
+            TestData {
+                version: "3.2",
+                requirement: ">3.1",
+                result: Ok(true),
+            },
+            // invalid operators
+            TestData {
+                version: "3.2.0",
+                requirement: "==3.2.0",
+                result: Err(anyhow!("invalid version operator \"==\"")),
+            },
+            TestData {
+                version: "3.2.0",
+                requirement: "=>3.2.0",
+                result: Err(anyhow!("invalid version operator \"=>\"")),
+            },
+            TestData {
+                version: "3.2.0",
+                requirement: ">=",
+                result: Err(anyhow!("invalid version comparison \">=\"")),
+            },
+            // four part versions
+            TestData {
+                version: "3.2.0.1",
+                requirement: ">=3.0.0",
+                result: Err(anyhow!("invalid version \"3.2.0.1\"")),
+            },
+            TestData {
+                version: "3.2.0",
+                requirement: "<3.2.0.1",
+                result: Err(anyhow!("invalid version \"3.2.0.1\"")),
+            },
+        ];
+
+        for (i, d) in tests.iter().enumerate() {
+            let msg = format!("test[{}]: {:?}", i, d);
+
+            let result = version_matches(d.version, d.requirement);
+
+            let msg = format!("{}, result: {:?}", msg, result);
+
+            match &d.result {
+                Ok(expected) => assert_eq!(result.unwrap(), *expected, "{}", msg),
+                Err(expected) => assert_eq!(
+                    result.unwrap_err().to_string(),
+                    expected.to_string(),
+                    "{}",
+                    msg
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_mount_propagation() {
+        #[derive(Debug)]
+        struct TestData<'a> {
+            mount_point: &'a str,
+            result: Option<Vec<&'a str>>,
+        }
+
+        let mountinfo = "\
+22 1 0:21 / / rw,relatime shared:1 - overlay overlay rw
+23 22 0:22 / /data rw,relatime shared:5 - ext4 /dev/vda rw
+24 22 0:23 / /slave rw,relatime master:5 - ext4 /dev/vdb rw
+25 22 0:24 / /both rw,relatime shared:7 master:5 - ext4 /dev/vdc rw
+26 22 0:25 / /unbindable rw,relatime unbindable - tmpfs tmpfs rw
+27 22 0:26 / /private rw,relatime - tmpfs tmpfs rw
+28 22 0:27 / /with\\040space rw,relatime shared:9 - tmpfs tmpfs rw
+29 23 0:28 / /data rw,relatime - tmpfs tmpfs rw
+";
+
+        let tests = &[
+            // the topmost of the mounts stacked on /data
+            TestData {
+                mount_point: "/data",
+                result: Some(vec!["private"]),
+            },
+            TestData {
+                mount_point: "/",
+                result: Some(vec!["shared"]),
+            },
+            TestData {
+                mount_point: "/slave",
+                result: Some(vec!["slave"]),
+            },
+            TestData {
+                mount_point: "/both",
+                result: Some(vec!["shared", "slave"]),
+            },
+            TestData {
+                mount_point: "/unbindable",
+                result: Some(vec!["unbindable"]),
+            },
+            TestData {
+                mount_point: "/private",
+                result: Some(vec!["private"]),
+            },
+            TestData {
+                mount_point: "/with space",
+                result: Some(vec!["shared"]),
+            },
+            TestData {
+                mount_point: "/missing",
+                result: None,
+            },
+        ];
+
+        for (i, d) in tests.iter().enumerate() {
+            let msg = format!("test[{}]: {:?}", i, d);
+
+            let result = get_mount_propagation(mountinfo, d.mount_point);
+            let expected = d
+                .result
+                .as_ref()
+                .map(|tags| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>());
+
+            assert_eq!(result, expected, "{}", msg);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright (c) 2026 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Description: Errors reported by the test VM boot.
+
+use std::fmt;
+
+// Boot step the test vm failed at, for the callers to tell a setup that
+// cannot work (unsupported hypervisor, bad configuration) from one that
+// failed along the way
+#[derive(Debug)]
+pub enum VmError {
+    UnsupportedHypervisor(String),
+    ConfigLoad(anyhow::Error),
+    PrepareVm(anyhow::Error),
+    DeviceHotplug(anyhow::Error),
+    StartVm(anyhow::Error),
+    SharedFs(anyhow::Error),
+    // the boot got cancelled by a signal
+    Cancelled,
+    // the runtime driving the boot could not be set up
+    Runtime(anyhow::Error),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::UnsupportedHypervisor(name) => write!(f, "unsupported hypervisor: {name}"),
+            VmError::ConfigLoad(_) => write!(f, "loading the test vm configuration"),
+            VmError::PrepareVm(_) => write!(f, "preparing the test vm"),
+            VmError::DeviceHotplug(_) => write!(f, "attaching the test vm devices"),
+            VmError::StartVm(_) => write!(f, "starting the test vm"),
+            VmError::SharedFs(_) => write!(f, "setting up the test vm file sharing"),
+            VmError::Cancelled => write!(f, "test vm boot cancelled"),
+            VmError::Runtime(_) => write!(f, "setting up the test vm runtime"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmError::UnsupportedHypervisor(_) | VmError::Cancelled => None,
+            VmError::ConfigLoad(e)
+            | VmError::PrepareVm(e)
+            | VmError::DeviceHotplug(e)
+            | VmError::StartVm(e)
+            | VmError::SharedFs(e)
+            | VmError::Runtime(e) => Some(&**e),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright (c) 2026 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Description: Hypervisor stand-in recording the device hotplugs, to test
+// the test vm device and storage handling without booting a vm.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hypervisor::device::DeviceType;
+use hypervisor::hypervisor_persist::HypervisorState;
+use hypervisor::{Hypervisor, MemoryConfig, PciPath, VcpuThreadIds, KATA_BLK_DEV_TYPE};
+use kata_types::capabilities::{Capabilities, CapabilityBits};
+use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Slot of the first hotplugged disk, the following ones being numbered on
+// from there, as on the root bus of a real vm
+const MOCK_FIRST_PCI_SLOT: u32 = 2;
+
+const MOCK_AGENT_SOCKET: &str = "vsock://3:1024";
+
+#[derive(Debug, Default)]
+pub struct MockHypervisor {
+    config: HypervisorConfig,
+    // hotplug calls, in the order they were made
+    added: Mutex<Vec<DeviceType>>,
+    updated: Mutex<Vec<DeviceType>>,
+    removed: Mutex<Vec<DeviceType>>,
+    stop_calls: AtomicUsize,
+    // fail the next stop_vm call
+    fail_stop: AtomicBool,
+    // hand out no guest address for the disks
+    no_disk_address: bool,
+    // fail the hotplug once this many devices got added
+    fail_add_at: Option<usize>,
+}
+
+impl MockHypervisor {
+    pub fn new(config: HypervisorConfig) -> Self {
+        MockHypervisor {
+            config,
+            ..Default::default()
+        }
+    }
+
+    // Mock whose disks end up without a guest address, as when the
+    // hypervisor fails to report it
+    pub fn without_disk_address(config: HypervisorConfig) -> Self {
+        MockHypervisor {
+            no_disk_address: true,
+            ..MockHypervisor::new(config)
+        }
+    }
+
+    // Mock failing to hotplug any device past the first count ones
+    pub fn failing_add_after(config: HypervisorConfig, count: usize) -> Self {
+        MockHypervisor {
+            fail_add_at: Some(count),
+            ..MockHypervisor::new(config)
+        }
+    }
+
+    pub fn added(&self) -> Vec<DeviceType> {
+        self.added.lock().unwrap().clone()
+    }
+
+    pub fn updated(&self) -> Vec<DeviceType> {
+        self.updated.lock().unwrap().clone()
+    }
+
+    pub fn removed(&self) -> Vec<DeviceType> {
+        self.removed.lock().unwrap().clone()
+    }
+
+    pub fn stop_calls(&self) -> usize {
+        self.stop_calls.load(Ordering::SeqCst)
+    }
+
+    pub fn fail_next_stop(&self) {
+        self.fail_stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl Hypervisor for MockHypervisor {
+    async fn prepare_vm(
+        &self,
+        _id: &str,
+        _netns: Option<String>,
+        _annotations: &HashMap<String, String>,
+        _selinux_label: Option<String>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn start_vm(&self, _timeout: i32) -> Result<()> {
+        Ok(())
+    }
+
+    async fn stop_vm(&self) -> Result<()> {
+        self.stop_calls.fetch_add(1, Ordering::SeqCst);
+
+        if self.fail_stop.swap(false, Ordering::SeqCst) {
+            return Err(anyhow!("mock vm failed to stop"));
+        }
+        Ok(())
+    }
+
+    async fn wait_vm(&self) -> Result<i32> {
+        Ok(0)
+    }
+
+    async fn pause_vm(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn save_vm(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn resume_vm(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn resize_vcpu(&self, old_vcpus: u32, new_vcpus: u32) -> Result<(u32, u32)> {
+        Ok((old_vcpus, new_vcpus))
+    }
+
+    async fn resize_memory(&self, new_mem_mb: u32) -> Result<(u32, MemoryConfig)> {
+        Ok((new_mem_mb, MemoryConfig::default()))
+    }
+
+    // Disks on the pci driver get the next free slot
+    async fn add_device(&self, device: DeviceType) -> Result<DeviceType> {
+        let mut added = self.added.lock().unwrap();
+
+        if self.fail_add_at == Some(added.len()) {
+            return Err(anyhow!("mock hotplug failure"));
+        }
+
+        let device = match device {
+            DeviceType::Block(mut blk) if !self.no_disk_address => {
+                let index = added
+                    .iter()
+                    .filter(|d| matches!(d, DeviceType::Block(_)))
+                    .count() as u32;
+
+                if blk.config.driver_option == KATA_BLK_DEV_TYPE {
+                    blk.config.pci_path = Some(PciPath::try_from(MOCK_FIRST_PCI_SLOT + index)?);
+                }
+                DeviceType::Block(blk)
+            }
+            device => device,
+        };
+
+        added.push(device.clone());
+        Ok(device)
+    }
+
+    async fn remove_device(&self, device: DeviceType) -> Result<()> {
+        self.removed.lock().unwrap().push(device);
+        Ok(())
+    }
+
+    async fn update_device(&self, device: DeviceType) -> Result<()> {
+        self.updated.lock().unwrap().push(device);
+        Ok(())
+    }
+
+    async fn get_agent_socket(&self) -> Result<String> {
+        Ok(MOCK_AGENT_SOCKET.to_string())
+    }
+
+    async fn disconnect(&self) {}
+
+    async fn hypervisor_config(&self) -> HypervisorConfig {
+        self.config.clone()
+    }
+
+    async fn get_thread_ids(&self) -> Result<VcpuThreadIds> {
+        Ok(VcpuThreadIds::default())
+    }
+
+    async fn get_pids(&self) -> Result<Vec<u32>> {
+        Ok(Vec::new())
+    }
+
+    async fn get_vmm_master_tid(&self) -> Result<u32> {
+        Ok(0)
+    }
+
+    async fn get_ns_path(&self) -> Result<String> {
+        Ok(String::new())
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_jailer_root(&self) -> Result<String> {
+        Ok(String::new())
+    }
+
+    async fn save_state(&self) -> Result<HypervisorState> {
+        Ok(HypervisorState::default())
+    }
+
+    // Disk hotplug along with file sharing, if a backend is configured
+    async fn capabilities(&self) -> Result<Capabilities> {
+        let mut caps = Capabilities::default();
+
+        if self.config.shared_fs.shared_fs.is_some() {
+            caps.set(
+                CapabilityBits::BlockDeviceSupport
+                    | CapabilityBits::BlockDeviceHotplugSupport
+                    | CapabilityBits::FsSharingSupport,
+            );
+        } else {
+            caps.set(
+                CapabilityBits::BlockDeviceSupport | CapabilityBits::BlockDeviceHotplugSupport,
+            );
+        }
+
+        Ok(caps)
+    }
+
+    async fn get_hypervisor_metrics(&self) -> Result<String> {
+        Err(anyhow!("no metrics for the mock hypervisor"))
+    }
+
+    async fn set_capabilities(&self, _flag: CapabilityBits) {}
+
+    async fn set_guest_memory_block_size(&self, _size: u32) {}
+
+    async fn guest_memory_block_size(&self) -> u32 {
+        0
+    }
+
+    async fn get_passfd_listener_addr(&self) -> Result<(String, u32)> {
+        Err(anyhow!("no passfd listener for the mock hypervisor"))
+    }
+}
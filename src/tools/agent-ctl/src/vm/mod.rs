@@ -5,19 +5,89 @@
 // Description: Boot UVM for testing container storages/volumes.
 
 use anyhow::{anyhow, Context, Result};
+use error::VmError;
+use hypervisor::device::device_manager::DeviceManager;
 use hypervisor::Hypervisor;
-use kata_types::config::{hypervisor::HYPERVISOR_NAME_CH, hypervisor::HYPERVISOR_NAME_QEMU};
+#[cfg(all(
+    feature = "cloud-hypervisor",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use kata_types::config::hypervisor::HYPERVISOR_NAME_CH;
+#[cfg(all(
+    feature = "dragonball",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use kata_types::config::hypervisor::HYPERVISOR_NAME_DRAGONBALL;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use kata_types::config::hypervisor::HYPERVISOR_NAME_FIRECRACKER;
+use kata_types::config::hypervisor::HYPERVISOR_NAME_QEMU;
+use protocols::agent::Storage;
+use serde::{Deserialize, Serialize};
 use share_fs_utils::SharedFs;
-use slog::info;
+use slog::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
 
+pub mod error;
+#[cfg(test)]
+mod mock_hypervisor;
 mod share_fs_utils;
 mod vm_ops;
 pub mod vm_utils;
 
+// Directory holding the state files of the named test vm sessions
+const SESSIONS_DIR: &str = "/run/agent-ctl/sessions";
+const SESSION_STATE_EXT: &str = "json";
+
 lazy_static! {
-    pub(crate) static ref SUPPORTED_VMMS: Vec<&'static str> =
-        vec![HYPERVISOR_NAME_CH, HYPERVISOR_NAME_QEMU];
+    // Hypervisors the tool is built with, gated as their drivers are
+    pub(crate) static ref SUPPORTED_VMMS: Vec<&'static str> = vec![
+        #[cfg(all(
+            feature = "cloud-hypervisor",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        HYPERVISOR_NAME_CH,
+        #[cfg(all(
+            feature = "dragonball",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        HYPERVISOR_NAME_DRAGONBALL,
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        HYPERVISOR_NAME_FIRECRACKER,
+        HYPERVISOR_NAME_QEMU,
+    ];
+
+    // Cancelled on SIGINT or SIGTERM to abort the ongoing test vm operations
+    static ref CANCEL_TOKEN: CancellationToken = CancellationToken::new();
+
+    // Runtime shared by the test vm operations. Its worker thread keeps the
+    // tasks spawned while booting (hypervisor and virtiofsd output readers,
+    // device handling) running in between the operations, until the vm is
+    // stopped.
+    static ref RUNTIME: std::io::Result<Runtime> = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build();
+}
+
+fn runtime() -> Result<&'static Runtime> {
+    RUNTIME
+        .as_ref()
+        .map_err(|e| anyhow!("failed to create the test vm runtime: {}", e))
+}
+
+// Run a test vm operation to completion on the shared runtime
+fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    Ok(runtime()?.block_on(future))
 }
 
 #[derive(Clone)]
@@ -26,34 +96,487 @@ pub struct TestVm {
     pub hypervisor_instance: Arc<dyn Hypervisor>,
     pub socket_addr: String,
     pub hybrid_vsock: bool,
+    // port the agent ttrpc server listens on in the guest
+    pub agent_port: u32,
     pub share_fs: SharedFs,
+    pub rootfs_share_pid: u32,
+    // virtiofs daemons of the extra shares, and the storages mounting them
+    pub extra_share_pids: Vec<u32>,
+    pub extra_share_storages: Vec<Storage>,
+    pub scratch_disks: Vec<ScratchDisk>,
+    pub iso_storages: Vec<Storage>,
+    pub pmem_storage: Option<Storage>,
+    // tap device created for the vm, empty if none or an existing one
+    pub created_tap: String,
+    pub vfio_devices: Vec<VfioPassthrough>,
+    pub device_manager: Arc<RwLock<DeviceManager>>,
+    // device ids of the hotplugged disks, unplugged when stopping the vm
+    pub block_device_ids: Vec<String>,
+    pub stop_timeout: Option<Duration>,
+    pub stop_kill: bool,
+    pub boot_timings: BootTimings,
+    // set by the first stop, shared by the clones of the instance
+    pub stopped: Arc<AtomicBool>,
+}
+
+impl TestVm {
+    // Whether the hypervisor reported an agent socket to connect to, a vm
+    // without one only being returned when booted to keep it
+    pub fn agent_reachable(&self) -> bool {
+        !self.socket_addr.is_empty()
+    }
+}
+
+// Host side state of a booted test VM, persisted so that the VM can be
+// torn down from another invocation of the tool
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TestVmState {
+    pub hypervisor_name: String,
+    pub socket_addr: String,
+    pub hypervisor_pid: u32,
+    pub virtiofsd_pid: u32,
+    pub shared_path: String,
+    #[serde(default)]
+    pub rootfs_share_pid: u32,
+    #[serde(default)]
+    pub extra_share_pids: Vec<u32>,
+    #[serde(default)]
+    pub scratch_disks: Vec<ScratchDisk>,
+    #[serde(default)]
+    pub created_tap: String,
+    // start times of the hypervisor and virtiofsd processes by pid, telling
+    // them apart from processes reusing their pids later on
+    #[serde(default)]
+    pub process_start_times: HashMap<u32, u64>,
+}
+
+// Host PCI device passed through to the test vm, along with its guest PCI
+// path, empty if assigned by the hypervisor
+#[derive(Clone, Debug, Default)]
+pub struct VfioPassthrough {
+    pub bdf: String,
+    pub guest_pci_path: String,
+}
+
+// Formatted scratch disk requested for the test vm
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ScratchDiskConfig {
+    pub size_mb: u64,
+    pub fstype: String,
+    pub readonly: bool,
+    // block driver the disk is attached with, None using the configured one
+    pub block_driver: Option<String>,
+}
+
+// Filesystem image attached as a persistent memory device
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PmemImageConfig {
+    pub path: String,
+    pub fstype: String,
+}
+
+// Tmpfs mounted in the guest as an ephemeral storage, of SIZE bytes and
+// with the octal mode if given
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TmpfsConfig {
+    pub size: u64,
+    pub mode: Option<u32>,
+}
+
+// Host directory shared with the test vm over a virtio-fs device of its
+// own, which the agent mounts at the guest path on sandbox creation
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VirtioFsShareConfig {
+    pub host_path: String,
+    pub tag: String,
+    pub guest_path: String,
+}
+
+// Scratch disk attached to the test vm: the backing image, the loop
+// device exposing it and the storage to mount it in the guest
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ScratchDisk {
+    pub image: String,
+    pub loop_device: String,
+    #[serde(skip)]
+    pub storage: Storage,
+}
+
+// Per run settings applied on top of the hypervisor configuration
+#[derive(Clone, Debug, Default)]
+pub struct TestVmConfig {
+    // Kata configuration file of the hypervisor, None using its default one
+    pub config_path: Option<String>,
+    // Number of independent IO threads for virtio-blk devices (qemu only)
+    pub io_threads: u32,
+    // Host rootfs directory booted over virtio-fs instead of the image (qemu only)
+    pub rootfs_dir: String,
+    // Formatted scratch disks attached to the vm
+    pub scratch_disks: Vec<ScratchDiskConfig>,
+    // How many scratch disk images are created at once, 0 using the
+    // default
+    pub attach_jobs: usize,
+    // Preallocate the guest memory
+    pub mem_prealloc: bool,
+    // Back the guest memory with hugepages of the configured type
+    pub hugepages: bool,
+    // Whether the guest memory is shared with the host, None leaving it to
+    // the hypervisor, which shares it whenever virtio-fs is in use
+    pub mem_shared: Option<bool>,
+    // ISO9660 images attached read-only
+    pub iso_images: Vec<String>,
+    // Filesystem image attached as an nvdimm device (qemu only)
+    pub pmem_image: Option<PmemImageConfig>,
+    // Tap device backing a network device, created if missing, empty for
+    // no network
+    pub tap_device: String,
+    // Host PCI addresses of the devices passed through with VFIO
+    pub vfio_devices: Vec<String>,
+    // Host directories shared over virtio-fs besides the main share
+    pub virtiofs_shares: Vec<VirtioFsShareConfig>,
+    // virtio-fs cache mode (never, auto or always), None using the
+    // configured one
+    pub virtio_fs_cache: Option<String>,
+    // Size in bytes of the virtio-fs DAX window, None using the configured
+    // one
+    pub virtio_fs_dax_window: Option<u64>,
+    // How long the hypervisor is given to start the vm, None using the
+    // default
+    pub boot_timeout: Option<Duration>,
+    // How long to wait for the hypervisor to exit once the vm is stopped,
+    // None not waiting at all
+    pub stop_timeout: Option<Duration>,
+    // Kill the hypervisor if it did not exit within the stop timeout
+    pub stop_kill: bool,
+    // Return the vm still running should the hypervisor report no agent
+    // socket, rather than tearing it down
+    pub keep_without_agent: bool,
+}
+
+// Time spent in each of the test VM boot phases
+#[derive(Clone, Debug, Default)]
+pub struct BootTimings {
+    pub config_load: Duration,
+    pub prepare: Duration,
+    // each group of devices attached, in attach order
+    pub devices: Vec<(String, Duration)>,
+    pub share_fs: Duration,
+    pub start: Duration,
+}
+
+// Version of a binary referenced by the hypervisor configuration
+#[derive(Clone, Debug)]
+pub struct BinaryVersion {
+    pub name: String,
+    pub path: String,
+    pub version: String,
+}
+
+// Device models and features actually in use by a booted test VM, which
+// can differ from the requested ones when the hypervisor falls back
+#[derive(Clone, Debug)]
+pub struct NegotiatedFeatures {
+    pub block_driver: String,
+    pub rootfs_driver: String,
+    pub shared_fs: String,
+    pub vsock: String,
+    pub io_threads: u32,
 }
 
 // Helper method to boot a test pod VM
-pub fn setup_vm(hypervisor_name: &str) -> Result<TestVm> {
+pub fn setup_vm(hypervisor_name: &str, vm_config: &TestVmConfig) -> Result<TestVm, VmError> {
+    block_on(setup_vm_async(hypervisor_name, vm_config)).map_err(VmError::Runtime)?
+}
+
+// Boot a test pod VM from within an async context, which the blocking
+// helpers cannot be called from
+pub async fn setup_vm_async(
+    hypervisor_name: &str,
+    vm_config: &TestVmConfig,
+) -> Result<TestVm, VmError> {
     info!(
         sl!(),
         "booting a pod vm using hypervisor:{:?}", hypervisor_name
     );
 
+    if !SUPPORTED_VMMS.contains(&hypervisor_name) {
+        return Err(VmError::UnsupportedHypervisor(hypervisor_name.to_string()));
+    }
+
+    vm_ops::boot_vm(hypervisor_name, vm_config, &CANCEL_TOKEN).await
+}
+
+// Helper method to validate the boot of a test pod VM without starting it
+pub fn check_vm_boot(hypervisor_name: &str, vm_config: &TestVmConfig) -> Result<()> {
     if !SUPPORTED_VMMS.contains(&hypervisor_name) {
         return Err(anyhow!("Unsupported hypervisor:{}", hypervisor_name));
     }
 
-    tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?
-        .block_on(vm_ops::boot_vm(hypervisor_name))
-        .context("booting the test vm")
+    block_on(vm_ops::check_boot(
+        hypervisor_name,
+        vm_config,
+        &CANCEL_TOKEN,
+    ))?
+    .context("preparing the test vm")
+}
+
+// Handle SIGINT and SIGTERM by cancelling the ongoing test vm operations,
+// so that the vm gets torn down (vm stopped, shares unmounted, virtiofsd
+// killed) rather than leaked by an abrupt exit. A second signal exits
+// immediately.
+pub fn cancel_on_signals() -> Result<()> {
+    let runtime = runtime()?;
+
+    // the signal stream has to be registered from within the runtime
+    let mut sigterm = {
+        let _guard = runtime.enter();
+        signal(SignalKind::terminate()).context("handle SIGTERM")?
+    };
+
+    runtime.spawn(async move {
+        if !next_signal(&mut sigterm).await {
+            return;
+        }
+
+        warn!(sl!(), "interrupted, tearing down the test vm");
+        CANCEL_TOKEN.cancel();
+
+        if next_signal(&mut sigterm).await {
+            std::process::exit(libc::EXIT_FAILURE);
+        }
+    });
+
+    Ok(())
+}
+
+// Wait for SIGINT or SIGTERM, returning false should they no longer be
+// received
+async fn next_signal(sigterm: &mut Signal) -> bool {
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.is_ok(),
+        received = sigterm.recv() => received.is_some(),
+    }
+}
+
+// Returns true once the test vm operations have been cancelled
+pub fn is_cancelled() -> bool {
+    CANCEL_TOKEN.is_cancelled()
 }
 
 // Helper method to stop a test pod VM
 pub fn remove_vm(instance: TestVm) -> Result<()> {
+    block_on(remove_vm_async(instance))?
+}
+
+// Stop a test pod VM from within an async context
+pub async fn remove_vm_async(instance: TestVm) -> Result<()> {
     info!(sl!(), "Stopping booted pod vm");
 
-    tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?
-        .block_on(vm_ops::stop_vm(instance))
+    vm_ops::stop_vm(instance)
+        .await
         .context("stopping the test vm")
 }
+
+// Helper method to save the state of a booted test VM to a file
+pub fn save_vm_state(instance: &TestVm, state_file: &str) -> Result<()> {
+    if vm_ops::is_in_process(&instance.hypervisor_name) {
+        return Err(anyhow!(
+            "the {} vm runs in-process and cannot outlive the tool",
+            instance.hypervisor_name
+        ));
+    }
+
+    let hypervisor_pid = block_on(instance.hypervisor_instance.get_vmm_master_tid())?
+        .context("get hypervisor pid")?;
+
+    let process_start_times = [
+        hypervisor_pid,
+        instance.share_fs.pid,
+        instance.rootfs_share_pid,
+    ]
+    .iter()
+    .chain(instance.extra_share_pids.iter())
+    .filter(|pid| **pid != 0)
+    .filter_map(|pid| vm_utils::process_start_time(*pid).map(|start_time| (*pid, start_time)))
+    .collect();
+
+    let state = TestVmState {
+        hypervisor_name: instance.hypervisor_name.clone(),
+        socket_addr: instance.socket_addr.clone(),
+        hypervisor_pid,
+        virtiofsd_pid: instance.share_fs.pid,
+        shared_path: instance.share_fs.shared_path.clone(),
+        rootfs_share_pid: instance.rootfs_share_pid,
+        extra_share_pids: instance.extra_share_pids.clone(),
+        scratch_disks: instance.scratch_disks.clone(),
+        created_tap: instance.created_tap.clone(),
+        process_start_times,
+    };
+
+    let data = serde_json::to_string_pretty(&state)?;
+    fs::write(state_file, data).with_context(|| format!("write vm state to {state_file}"))
+}
+
+// Helper method to tear down a test VM from a state file saved by a
+// previous invocation, removing the state file once done
+pub fn teardown_vm_state(state_file: &str) -> Result<()> {
+    let data =
+        fs::read_to_string(state_file).with_context(|| format!("read vm state {state_file}"))?;
+    let state: TestVmState = serde_json::from_str(&data).context("parse vm state")?;
+
+    info!(sl!(), "tearing down test vm"; "state" => format!("{:?}", state));
+
+    block_on(vm_ops::teardown_vm(&state))?.context("tearing down the test vm")?;
+
+    fs::remove_file(state_file).with_context(|| format!("remove vm state {state_file}"))
+}
+
+// Path of the state file of a named session, checking the name is usable
+// as a file name and, if the session is to be created, that it is unique
+pub fn session_state_file(name: &str, create: bool) -> Result<String> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(anyhow!("invalid session name {:?}", name));
+    }
+
+    let state_file = format!("{SESSIONS_DIR}/{name}.{SESSION_STATE_EXT}");
+
+    if create {
+        fs::create_dir_all(SESSIONS_DIR)
+            .with_context(|| format!("create sessions directory {SESSIONS_DIR}"))?;
+
+        if Path::new(&state_file).exists() {
+            return Err(anyhow!("session {:?} already exists", name));
+        }
+    } else if !Path::new(&state_file).exists() {
+        return Err(anyhow!("no session {:?}", name));
+    }
+
+    Ok(state_file)
+}
+
+// Helper method to list the named sessions, along with the saved state of
+// their test VM
+pub fn list_sessions() -> Result<Vec<(String, TestVmState)>> {
+    let entries = match fs::read_dir(SESSIONS_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("read sessions directory {}: {}", SESSIONS_DIR, e)),
+    };
+
+    let mut sessions = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+
+        if path.extension().and_then(|e| e.to_str()) != Some(SESSION_STATE_EXT) {
+            continue;
+        }
+
+        let name = match path.file_stem().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        let state = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| serde_json::from_str::<TestVmState>(&data).map_err(Into::into));
+
+        match state {
+            Ok(state) => sessions.push((name, state)),
+            Err(e) => warn!(sl!(), "ignoring session {}: {:?}", name, e),
+        }
+    }
+
+    sessions.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(sessions)
+}
+
+// Helper method to pause a booted test VM
+pub fn pause_test_vm(instance: &TestVm) -> Result<()> {
+    block_on(vm_ops::pause_vm(instance))?
+}
+
+// Helper method to resume a paused test VM
+pub fn resume_test_vm(instance: &TestVm) -> Result<()> {
+    block_on(vm_ops::resume_vm(instance))?
+}
+
+// Helper method to resize the vcpus of a booted test VM, returning their
+// resulting number
+pub fn resize_vcpus(instance: &TestVm, count: u32) -> Result<u32> {
+    block_on(vm_ops::resize_vcpus(instance, count))?
+}
+
+// Helper method to resize the memory of a booted test VM, returning its
+// resulting size in megabytes
+pub fn resize_memory(instance: &TestVm, size_mb: u32) -> Result<u32> {
+    block_on(vm_ops::resize_memory(instance, size_mb))?
+}
+
+// Helper method to report the features negotiated for a booted test VM
+pub fn negotiated_features(instance: &TestVm) -> Result<NegotiatedFeatures> {
+    block_on(vm_ops::negotiated_features(instance))?.context("query the test vm features")
+}
+
+// Helper method to retrieve the kernel command line of a booted test VM
+pub fn kernel_cmdline(instance: &TestVm) -> Result<String> {
+    block_on(vm_ops::kernel_cmdline(instance))?.context("get the test vm kernel command line")
+}
+
+// Helper method to get the configuration a test VM would boot with, the
+// per run settings applied, serialized back to TOML
+pub fn dump_effective_config(hypervisor_name: &str, vm_config: &TestVmConfig) -> Result<String> {
+    if !SUPPORTED_VMMS.contains(&hypervisor_name) {
+        return Err(anyhow!("Unsupported hypervisor:{}", hypervisor_name));
+    }
+
+    let (mut toml_config, hypervisor_config, _) =
+        vm_ops::load_vm_config(hypervisor_name, vm_config)?;
+    toml_config
+        .hypervisor
+        .insert(hypervisor_name.to_string(), hypervisor_config);
+
+    // going through a value emits the plain values ahead of the tables
+    let value = toml::Value::try_from(&toml_config).context("convert the configuration")?;
+    toml::to_string(&value).context("serialize the configuration")
+}
+
+// Helper method to retrieve the versions of the configured hypervisor
+// and virtiofsd binaries, without booting a VM
+pub fn binary_versions(
+    hypervisor_name: &str,
+    config_path: Option<&str>,
+) -> Result<Vec<BinaryVersion>> {
+    if !SUPPORTED_VMMS.contains(&hypervisor_name) {
+        return Err(anyhow!("Unsupported hypervisor:{}", hypervisor_name));
+    }
+
+    let (toml_config, _) = vm_ops::load_hypervisor_config(hypervisor_name, config_path)?;
+
+    let hypervisor_config = toml_config
+        .hypervisor
+        .get(hypervisor_name)
+        .ok_or_else(|| anyhow!("Failed to get hypervisor config"))?;
+
+    let mut binaries = vec![(hypervisor_name, hypervisor_config.path.clone())];
+
+    let virtiofsd = &hypervisor_config.shared_fs.virtio_fs_daemon;
+    if !virtiofsd.is_empty() {
+        binaries.push(("virtiofsd", virtiofsd.clone()));
+    }
+
+    let mut versions = Vec::new();
+    for (name, path) in binaries {
+        let version =
+            vm_utils::get_binary_version(&path).with_context(|| format!("get {name} version"))?;
+
+        versions.push(BinaryVersion {
+            name: name.to_string(),
+            path,
+            version,
+        });
+    }
+
+    Ok(versions)
+}
@@ -4,18 +4,20 @@
 //
 // Description: Helper to setup virtio-fs shared path between host & guest
 
+use crate::vm::{vm_ops::handle_device, vm_utils};
 use anyhow::{anyhow, Context, Result};
 use hypervisor::Hypervisor;
 use hypervisor::{
     device::{
-        device_manager::{do_handle_device, DeviceManager},
+        device_manager::{do_update_device, DeviceManager},
         DeviceConfig,
     },
-    ShareFsConfig,
+    ShareFsConfig, ShareFsMountConfig, ShareFsMountOperation, ShareFsMountType,
 };
 use kata_types::config::hypervisor::SharedFsInfo;
 use slog::debug;
-use std::{path::Path, process::Stdio, sync::Arc};
+use std::io::Write;
+use std::{fs::File, path::Path, process::Stdio, sync::Arc};
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     process::{Child, Command},
@@ -29,6 +31,21 @@ pub const VIRTIO_FS: &str = "virtio-fs";
 pub const MOUNT_GUEST_TAG: &str = "kataShared";
 const VIRTIO_FS_SOCKET: &str = "virtiofsd.sock";
 
+// Extension of the file the output of a virtiofs daemon goes to, next to
+// its socket, and the number of its last lines reported on failure
+const VIRTIO_FS_LOG_EXT: &str = "log";
+const VIRTIO_FS_LOG_TAIL_LINES: usize = 20;
+
+// virtio-fs served by the hypervisor itself (dragonball), without a
+// virtiofs daemon. The shared path is exported as a passthrough fs mounted
+// under the guest tag at the given path.
+pub const INLINE_VIRTIO_FS: &str = "inline-virtio-fs";
+pub const INLINE_PASSTHROUGH_MOUNT: &str = "/containers";
+
+// Mount tag and daemon socket of the guest rootfs share
+pub const ROOTFS_GUEST_TAG: &str = "kataRootfs";
+const ROOTFS_VIRTIO_FS_SOCKET: &str = "rootfs-virtiofsd.sock";
+
 // Source: a rw root path created in /tmp and appended with the vm name
 pub const VIRTIO_FS_ROOT_PATH: &str = "/tmp";
 
@@ -36,12 +53,16 @@ pub const VIRTIO_FS_ROOT_PATH: &str = "/tmp";
 pub struct SharedFs {
     pub pid: u32,
     pub shared_path: String,
+    // sharing backend in use, as named in the hypervisor configuration
+    pub backend: String,
+    // output of the virtiofs daemon, empty if served inline
+    pub log_path: String,
 }
 
 // Setup up virtio-fs file share between host & guest.
 // a. Create the shared root path
 // b. Plugin the device in the guest VM
-// c. Start the virtiofs daemon
+// c. Start the virtiofs daemon, unless the hypervisor serves the share inline
 pub(crate) async fn setup_virtio_fs(
     hypervisor: Arc<dyn Hypervisor>,
     dev_mgr: Arc<RwLock<DeviceManager>>,
@@ -52,6 +73,154 @@ pub(crate) async fn setup_virtio_fs(
         return Ok(SharedFs::default());
     }
 
+    let shared_fs_info = hypervisor.hypervisor_config().await.shared_fs;
+    let inline = shared_fs_info.shared_fs.as_deref() == Some(INLINE_VIRTIO_FS);
+
+    let shared_fs_info = if inline {
+        shared_fs_info
+    } else {
+        get_virtio_fs_info(&hypervisor).await?
+    };
+
+    // Create the rootfs dir
+    let host_path = [VIRTIO_FS_ROOT_PATH, root_path].join("/");
+    std::fs::create_dir_all(&host_path).context("virtio-fs:: failed to create root path")?;
+    vm_utils::track_temp_path(&host_path);
+
+    // the daemon output is kept out of the share, unlike its socket
+    let log_path = if inline {
+        String::new()
+    } else {
+        log_path_of(&format!(
+            "{VIRTIO_FS_ROOT_PATH}/{root_path}-{VIRTIO_FS_SOCKET}"
+        ))
+    };
+
+    let result = if inline {
+        add_inline_virtio_fs_share(&shared_fs_info, &dev_mgr, &host_path)
+            .await
+            .map(|_| 0)
+    } else {
+        let sock_path = generate_sock_path(&host_path);
+        add_virtio_fs_share(
+            &shared_fs_info,
+            &dev_mgr,
+            &host_path,
+            &sock_path,
+            MOUNT_GUEST_TAG,
+            &log_path,
+        )
+        .await
+    };
+
+    let virtiofsd_pid = match result {
+        Ok(pid) => pid,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&host_path);
+            return Err(e);
+        }
+    };
+
+    Ok(SharedFs {
+        pid: virtiofsd_pid,
+        shared_path: host_path,
+        backend: shared_fs_info.shared_fs.unwrap_or_default(),
+        log_path,
+    })
+}
+
+// Share a host rootfs directory with the guest to be used as its root
+// filesystem. The directory is left untouched on teardown, so the daemon
+// socket lives next to the shared root path rather than inside it.
+// Returns the pid of the virtiofs daemon serving it.
+pub(crate) async fn setup_rootfs_share(
+    hypervisor: Arc<dyn Hypervisor>,
+    dev_mgr: Arc<RwLock<DeviceManager>>,
+    rootfs_dir: &str,
+    root_path: &str,
+) -> Result<u32> {
+    if !hypervisor.capabilities().await?.is_fs_sharing_supported() {
+        return Err(anyhow!(
+            "virtio-fs:: fs sharing not supported by the hypervisor"
+        ));
+    }
+
+    let sock_path = format!("{VIRTIO_FS_ROOT_PATH}/{root_path}-{ROOTFS_VIRTIO_FS_SOCKET}");
+
+    setup_dir_share(
+        &hypervisor,
+        &dev_mgr,
+        rootfs_dir,
+        ROOTFS_GUEST_TAG,
+        &sock_path,
+    )
+    .await
+    .context("virtio-fs:: share rootfs")
+}
+
+// Share a host directory with the guest over a virtio-fs device of its own,
+// under the mount tag. As for the rootfs, the directory is left untouched
+// on teardown. Returns the pid of the virtiofs daemon serving it.
+pub(crate) async fn setup_extra_share(
+    hypervisor: Arc<dyn Hypervisor>,
+    dev_mgr: Arc<RwLock<DeviceManager>>,
+    host_path: &str,
+    mount_tag: &str,
+    root_path: &str,
+) -> Result<u32> {
+    if !hypervisor.capabilities().await?.is_fs_sharing_supported() {
+        return Err(anyhow!(
+            "virtio-fs:: fs sharing not supported by the hypervisor"
+        ));
+    }
+
+    let sock_path = format!("{VIRTIO_FS_ROOT_PATH}/{root_path}-{mount_tag}-{VIRTIO_FS_SOCKET}");
+
+    setup_dir_share(&hypervisor, &dev_mgr, host_path, mount_tag, &sock_path)
+        .await
+        .with_context(|| format!("virtio-fs:: share {host_path} as {mount_tag}"))
+}
+
+async fn setup_dir_share(
+    hypervisor: &Arc<dyn Hypervisor>,
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    host_path: &str,
+    mount_tag: &str,
+    sock_path: &str,
+) -> Result<u32> {
+    let shared_fs_info = get_virtio_fs_info(hypervisor).await?;
+
+    vm_utils::track_temp_path(sock_path);
+
+    add_virtio_fs_share(
+        &shared_fs_info,
+        dev_mgr,
+        host_path,
+        sock_path,
+        mount_tag,
+        &log_path_of(sock_path),
+    )
+    .await
+}
+
+// Path of the daemon output file going along with a socket path
+fn log_path_of(sock_path: &str) -> String {
+    Path::new(sock_path)
+        .with_extension(VIRTIO_FS_LOG_EXT)
+        .to_string_lossy()
+        .to_string()
+}
+
+// Last lines of a virtiofs daemon output, for the errors to tell why it
+// failed
+pub(crate) fn log_tail(log_path: &str) -> String {
+    let log = std::fs::read_to_string(log_path).unwrap_or_default();
+    let lines: Vec<&str> = log.lines().collect();
+
+    lines[lines.len().saturating_sub(VIRTIO_FS_LOG_TAIL_LINES)..].join("\n")
+}
+
+async fn get_virtio_fs_info(hypervisor: &Arc<dyn Hypervisor>) -> Result<SharedFsInfo> {
     let shared_fs_info = hypervisor.hypervisor_config().await.shared_fs;
 
     let shared_fs = shared_fs_info.shared_fs.clone().unwrap_or_default();
@@ -60,10 +229,20 @@ pub(crate) async fn setup_virtio_fs(
         return Err(anyhow!("Unsupported virtio-fs type: {:?}", &shared_fs));
     }
 
-    // Create the rootfs dir
-    let host_path = [VIRTIO_FS_ROOT_PATH, root_path].join("/");
-    std::fs::create_dir_all(&host_path).context("virtio-fs:: failed to create root path")?;
+    Ok(shared_fs_info)
+}
 
+// Plugin a virtio-fs device sharing the host path under the mount tag and
+// start the virtiofs daemon serving it, its output going to the log file.
+// Returns the daemon pid.
+async fn add_virtio_fs_share(
+    shared_fs_info: &SharedFsInfo,
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    host_path: &str,
+    sock_path: &str,
+    mount_tag: &str,
+    log_path: &str,
+) -> Result<u32> {
     // plugin the device
     // Use queue size and num from hypervisor config, with fallback to sensible defaults
     // if not configured (e.g., 1024 queue size, 1 queue as per previous CH defaults)
@@ -76,9 +255,9 @@ pub(crate) async fn setup_virtio_fs(
     let queue_num: u64 = 1; // Default to 1 queue (previous CH default)
 
     let share_fs_config = ShareFsConfig {
-        host_shared_path: host_path.clone(),
-        sock_path: generate_sock_path(&host_path),
-        mount_tag: String::from(MOUNT_GUEST_TAG),
+        host_shared_path: host_path.to_string(),
+        sock_path: sock_path.to_string(),
+        mount_tag: String::from(mount_tag),
         fs_type: VIRTIO_FS.to_string(),
         queue_size,
         queue_num,
@@ -86,19 +265,55 @@ pub(crate) async fn setup_virtio_fs(
         mount_config: None,
     };
 
-    do_handle_device(&dev_mgr, &DeviceConfig::ShareFsCfg(share_fs_config))
+    handle_device(dev_mgr, &DeviceConfig::ShareFsCfg(share_fs_config))
         .await
         .context("virtio-fs:: add virtio-fs failed")?;
 
     // start the virtio fs daemon
-    let virtiofsd_pid = start_virtiofsd(shared_fs_info.clone(), &host_path)
+    start_virtiofsd(shared_fs_info.clone(), host_path, sock_path, log_path)
         .await
-        .context("virtio-fs:: starting daemon")?;
+        .context("virtio-fs:: starting daemon")
+}
 
-    Ok(SharedFs {
-        pid: virtiofsd_pid,
-        shared_path: host_path,
-    })
+// Plugin an inline virtio-fs device and have the hypervisor export the
+// host path through it, rather than a virtiofs daemon
+async fn add_inline_virtio_fs_share(
+    shared_fs_info: &SharedFsInfo,
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    host_path: &str,
+) -> Result<()> {
+    let share_fs_config = ShareFsConfig {
+        host_shared_path: host_path.to_string(),
+        mount_tag: String::from(MOUNT_GUEST_TAG),
+        fs_type: INLINE_VIRTIO_FS.to_string(),
+        queue_size: shared_fs_info.virtio_fs_queue_size as u64,
+        queue_num: 1,
+        ..Default::default()
+    };
+
+    handle_device(dev_mgr, &DeviceConfig::ShareFsCfg(share_fs_config))
+        .await
+        .context("virtio-fs:: add inline virtio-fs failed")?;
+
+    let mount_config = ShareFsMountConfig {
+        source: host_path.to_string(),
+        fstype: ShareFsMountType::PASSTHROUGH,
+        mount_point: INLINE_PASSTHROUGH_MOUNT.to_string(),
+        config: None,
+        tag: String::from(MOUNT_GUEST_TAG),
+        op: ShareFsMountOperation::Mount,
+        prefetch_list_path: None,
+    };
+
+    let share_fs_config = ShareFsConfig {
+        host_shared_path: host_path.to_string(),
+        mount_config: Some(mount_config),
+        ..Default::default()
+    };
+
+    do_update_device(dev_mgr, &DeviceConfig::ShareFsCfg(share_fs_config))
+        .await
+        .context("virtio-fs:: mount inline passthrough fs")
 }
 
 fn generate_sock_path(root: &str) -> String {
@@ -124,48 +339,79 @@ fn virtiofsd_args(cfg: SharedFsInfo, shared_dir: &str, sock_path: &str) -> Resul
     Ok(args)
 }
 
-async fn start_virtiofsd(share_fs_info: SharedFsInfo, root_path: &str) -> Result<u32> {
-    let sock_path = generate_sock_path(root_path);
+async fn start_virtiofsd(
+    share_fs_info: SharedFsInfo,
+    root_path: &str,
+    sock_path: &str,
+    log_path: &str,
+) -> Result<u32> {
     let args =
-        virtiofsd_args(share_fs_info.clone(), root_path, &sock_path).context("virtiofsd args")?;
+        virtiofsd_args(share_fs_info.clone(), root_path, sock_path).context("virtiofsd args")?;
+
+    let log = File::create(log_path).with_context(|| format!("create virtiofsd log {log_path}"))?;
+    vm_utils::track_temp_path(log_path);
 
     let mut cmd = Command::new(&share_fs_info.virtio_fs_daemon);
-    let child_cmd = cmd.args(&args).stderr(Stdio::piped());
+    let child_cmd = cmd
+        .args(&args)
+        .stdout(Stdio::from(log.try_clone()?))
+        .stderr(Stdio::piped());
     let child = child_cmd.spawn().context("spawn virtiofsd")?;
 
     let child_pid = child.id().unwrap_or_default();
 
     let (tx, mut rx): (Sender<Result<()>>, Receiver<Result<()>>) = channel(100);
-    tokio::spawn(run_virtiofsd(child, tx));
+    tokio::spawn(run_virtiofsd(child, tx, log));
 
-    match rx.recv().await.unwrap() {
-        Ok(_) => {
+    // the daemon exiting early closes the channel
+    match rx.recv().await {
+        Some(Ok(_)) => {
             debug!(sl!(), "started virtiofsd successfully");
         }
-        Err(e) => {
-            debug!(sl!(), "failed to start virtiofsd {}", e);
-            shutdown_virtiofsd(SharedFs {
-                pid: child_pid,
-                shared_path: root_path.to_string(),
-            })
-            .await
-            .context("shutdown_virtiofsd")?;
-            return Err(anyhow!("failed to start virtiofsd"));
+        result => {
+            if let Some(Err(e)) = result {
+                debug!(sl!(), "failed to start virtiofsd {}", e);
+            }
+            kill_virtiofsd(child_pid).context("kill_virtiofsd")?;
+            return Err(anyhow!(
+                "failed to start virtiofsd, output in {}:\n{}",
+                log_path,
+                log_tail(log_path)
+            ));
         }
     }
 
     Ok(child_pid)
 }
 
+// Kill the virtiofs daemon, if any as inline virtio-fs is served by the
+// hypervisor, and remove the shared path
 pub(crate) async fn shutdown_virtiofsd(info: SharedFs) -> Result<()> {
     debug!(sl!(), "virtio-fs:: shutdown virtiofsd pid {}", info.pid);
 
-    if info.pid == 0 {
+    if info.shared_path.is_empty() {
         debug!(sl!(), "virtio-fs: not running");
         return Ok(());
     }
 
-    let pid = ::nix::unistd::Pid::from_raw(info.pid as i32);
+    kill_virtiofsd(info.pid)?;
+
+    // the shared path may be gone with a previous shutdown
+    match std::fs::remove_dir_all(&info.shared_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).context("virtio-fs: Failed to delete shared path")
+        }
+        _ => Ok(()),
+    }
+}
+
+// Kill a virtiofs daemon, leaving the directory it serves in place
+pub(crate) fn kill_virtiofsd(pid: u32) -> Result<()> {
+    if pid == 0 {
+        return Ok(());
+    }
+
+    let pid = ::nix::unistd::Pid::from_raw(pid as i32);
 
     if let Err(err) = ::nix::sys::signal::kill(pid, nix::sys::signal::SIGKILL) {
         if err != ::nix::Error::ESRCH {
@@ -173,13 +419,10 @@ pub(crate) async fn shutdown_virtiofsd(info: SharedFs) -> Result<()> {
         }
     }
 
-    std::fs::remove_dir_all(&info.shared_path)
-        .context("virtio-fs: Failed to delete shared path")?;
-
     Ok(())
 }
 
-async fn run_virtiofsd(mut child: Child, tx: Sender<Result<()>>) -> Result<()> {
+async fn run_virtiofsd(mut child: Child, tx: Sender<Result<()>>, mut log: File) -> Result<()> {
     let stderr = child.stderr.as_mut().unwrap();
     let stderr_reader = BufReader::new(stderr);
     let mut lines = stderr_reader.lines();
@@ -188,6 +431,7 @@ async fn run_virtiofsd(mut child: Child, tx: Sender<Result<()>>) -> Result<()> {
         let trim_buffer = buffer.trim_end();
         if !trim_buffer.is_empty() {
             debug!(sl!(), "source: virtiofsd {}", trim_buffer);
+            let _ = writeln!(log, "{}", trim_buffer);
         }
         if buffer.contains("Waiting for vhost-user socket connection") {
             tx.send(Ok(())).await.unwrap();
@@ -4,33 +4,65 @@
 //
 // Description: Boot UVM for testing container storages/volumes.
 
-use crate::vm::{share_fs_utils, vm_utils, TestVm};
+use crate::vm::{
+    error::VmError, share_fs_utils, share_fs_utils::SharedFs, vm_utils, BootTimings,
+    NegotiatedFeatures, ScratchDisk, ScratchDiskConfig, TestVm, TestVmConfig, TestVmState,
+    VfioPassthrough,
+};
 use anyhow::{anyhow, Context, Result};
 #[cfg(all(
     feature = "cloud-hypervisor",
     any(target_arch = "x86_64", target_arch = "aarch64")
 ))]
 use hypervisor::ch::CloudHypervisor;
+#[cfg(all(
+    feature = "dragonball",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use hypervisor::dragonball::Dragonball;
 use hypervisor::{
     device::{
-        device_manager::{do_handle_device, DeviceManager},
-        DeviceConfig,
+        device_manager::{do_handle_device_within, DeviceManager},
+        DeviceConfig, DeviceType,
     },
+    get_vfio_device,
     qemu::Qemu,
-    BlockConfig, Hypervisor, VsockConfig,
+    Address, BlockConfig, Hypervisor, NetworkConfig, VfioConfig, VsockConfig, VIRTIO_BLOCK_CCW,
+    VIRTIO_BLOCK_MMIO, VIRTIO_BLOCK_PCI, VIRTIO_PMEM,
 };
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use hypervisor::{
+    firecracker::Firecracker, utils::get_hvsock_path, HybridVsockConfig, DEFAULT_GUEST_VSOCK_CID,
+};
+use kata_types::config::default::DEFAULT_AGENT_VSOCK_PORT;
+#[cfg(all(
+    feature = "dragonball",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use kata_types::config::DragonballConfig;
 use kata_types::config::{
-    hypervisor::register_hypervisor_plugin, hypervisor::TopologyConfigInfo,
-    hypervisor::HYPERVISOR_NAME_QEMU, QemuConfig,
+    hypervisor::register_hypervisor_plugin, hypervisor::Hypervisor as HypervisorConfig,
+    hypervisor::TopologyConfigInfo, hypervisor::HYPERVISOR_NAME_DRAGONBALL,
+    hypervisor::HYPERVISOR_NAME_QEMU, hypervisor::VIRTIO_SCSI, QemuConfig, TomlConfig,
 };
 #[cfg(all(
     feature = "cloud-hypervisor",
     any(target_arch = "x86_64", target_arch = "aarch64")
 ))]
 use kata_types::config::{hypervisor::HYPERVISOR_NAME_CH, CloudHypervisorConfig};
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use kata_types::config::{hypervisor::HYPERVISOR_NAME_FIRECRACKER, FirecrackerConfig};
+use kata_types::device::{DRIVER_BLK_CCW_TYPE, DRIVER_BLK_PCI_TYPE, DRIVER_SCSI_TYPE};
+use protocols::agent::Storage;
+use slog::{info, warn};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
 
 // Clh specific configuration path
 #[cfg(all(
@@ -40,6 +72,19 @@ use tokio::sync::RwLock;
 const CLH_CONFIG_PATH: &str =
     "/opt/kata/share/defaults/kata-containers/runtime-rs/configuration-clh-runtime-rs.toml";
 
+// Dragonball specific configuration path
+#[cfg(all(
+    feature = "dragonball",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const DB_CONFIG_PATH: &str =
+    "/opt/kata/share/defaults/kata-containers/runtime-rs/configuration-dragonball.toml";
+
+// Firecracker specific configuration path
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const FC_CONFIG_PATH: &str =
+    "/opt/kata/share/defaults/kata-containers/runtime-rs/configuration-rs-fc.toml";
+
 // qemu specific configuration path
 const QEMU_CONFIG_PATH: &str =
     "/opt/kata/share/defaults/kata-containers/runtime-rs/configuration-qemu-runtime-rs.toml";
@@ -47,18 +92,68 @@ const QEMU_CONFIG_PATH: &str =
 const VM_NAME: &str = "agent-ctl-testvm";
 const VM_START_TIMEOUT: i32 = 10_000;
 
-// Boot the test vm.
-// In summary, this method
-// - parses hypervisor specific kata config file
-// - loads hypervisor specific config
-// - instantiates a hypervisor object
-// - calls prepare_vm
-// - instantiates device manager to handle devices
-// - calls start_vm to boot pod vm
-// - retrieves the agent ttrpc server socket address
-pub(crate) async fn boot_vm(name: &str) -> Result<TestVm> {
+const ERR_BOOT_CANCELLED: &str = "test vm boot cancelled";
+
+// Upper bound to wait for the device manager lock before a device operation
+const DEV_MGR_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Interval between checks for the hypervisor exit after stopping the vm,
+// and the agent socket prefix of the hybrid vsock unix socket it leaves
+const HYPERVISOR_EXIT_INTERVAL: Duration = Duration::from_millis(100);
+const HYBRID_VSOCK_PREFIX: &str = "hvsock://";
+
+// Hypervisors a tap backed network device can be attached to, and which
+// VFIO devices can be passed through to
+const TAP_HYPERVISORS: &[&str] = &[
+    HYPERVISOR_NAME_QEMU,
+    kata_types::config::hypervisor::HYPERVISOR_NAME_CH,
+];
+const VFIO_HYPERVISORS: &[&str] = TAP_HYPERVISORS;
+
+// Hypervisors served by virtiofs daemons, which the extra virtio-fs shares
+// each get one of
+const VIRTIO_FS_SHARE_HYPERVISORS: &[&str] = TAP_HYPERVISORS;
+
+// Hypervisors actually pausing the vm, the others accepting the request
+// without doing anything
+const PAUSE_HYPERVISORS: &[&str] = &[HYPERVISOR_NAME_QEMU, HYPERVISOR_NAME_DRAGONBALL];
+
+// Hypervisors actually hotplugging vcpus and memory into a running vm
+const RESIZE_HYPERVISORS: &[&str] = &[
+    HYPERVISOR_NAME_QEMU,
+    kata_types::config::hypervisor::HYPERVISOR_NAME_CH,
+    HYPERVISOR_NAME_DRAGONBALL,
+];
+
+// Block drivers the hypervisor hotplugs disks with, which a scratch disk
+// can be given instead of the configured one
+fn scratch_block_drivers(name: &str) -> &'static [&'static str] {
+    match name {
+        HYPERVISOR_NAME_QEMU => &[VIRTIO_BLOCK_PCI, VIRTIO_BLOCK_CCW, VIRTIO_SCSI],
+        kata_types::config::hypervisor::HYPERVISOR_NAME_CH => &[VIRTIO_BLOCK_PCI],
+        HYPERVISOR_NAME_DRAGONBALL => &[VIRTIO_BLOCK_PCI, VIRTIO_BLOCK_MMIO],
+        // no pci bus in the vm
+        kata_types::config::hypervisor::HYPERVISOR_NAME_FIRECRACKER => &[VIRTIO_BLOCK_MMIO],
+        _ => &[],
+    }
+}
+
+// Number of scratch disk images created at once by default
+const DEFAULT_ATTACH_JOBS: usize = 2;
+
+// Device manager naming prefix of the VFIO devices passed through
+const VFIO_HOSTDEV_PREFIX: &str = "vfio_device_";
+
+// Register the hypervisor config plugin and load the hypervisor specific
+// kata config file, or the given one instead. Returns the parsed
+// configuration along with whether the hypervisor uses hybrid vsock for
+// the agent connection.
+pub(crate) fn load_hypervisor_config(
+    name: &str,
+    config_path: Option<&str>,
+) -> Result<(TomlConfig, bool)> {
     #[allow(clippy::needless_late_init)]
-    let config_path;
+    let default_path;
     #[allow(unused_mut)]
     let mut is_hybrid_vsock = false;
 
@@ -70,23 +165,219 @@ pub(crate) async fn boot_vm(name: &str) -> Result<TestVm> {
         ))]
         HYPERVISOR_NAME_CH => {
             register_hypervisor_plugin(HYPERVISOR_NAME_CH, Arc::new(CloudHypervisorConfig::new()));
-            config_path = CLH_CONFIG_PATH;
+            default_path = CLH_CONFIG_PATH;
+            is_hybrid_vsock = true;
+        }
+        #[cfg(all(
+            feature = "dragonball",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        HYPERVISOR_NAME_DRAGONBALL => {
+            register_hypervisor_plugin(
+                HYPERVISOR_NAME_DRAGONBALL,
+                Arc::new(DragonballConfig::new()),
+            );
+            default_path = DB_CONFIG_PATH;
+            is_hybrid_vsock = true;
+        }
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        HYPERVISOR_NAME_FIRECRACKER => {
+            register_hypervisor_plugin(
+                HYPERVISOR_NAME_FIRECRACKER,
+                Arc::new(FirecrackerConfig::new()),
+            );
+            default_path = FC_CONFIG_PATH;
             is_hybrid_vsock = true;
         }
         &_ => {
             register_hypervisor_plugin(HYPERVISOR_NAME_QEMU, Arc::new(QemuConfig::new()));
-            config_path = QEMU_CONFIG_PATH;
+            default_path = QEMU_CONFIG_PATH;
         }
     };
 
     // get the kata configuration toml
+    let config_path = config_path.unwrap_or(default_path);
     let toml_config = vm_utils::load_config(config_path)?;
 
-    let hypervisor_config = toml_config
+    // The agent kernel params get applied to the hypervisor named in the
+    // runtime section, which must thus be the one being booted.
+    if toml_config.runtime.hypervisor_name != name {
+        return Err(anyhow!(
+            "configuration {} is for hypervisor {:?}, not {:?}",
+            config_path,
+            toml_config.runtime.hypervisor_name,
+            name
+        ));
+    }
+
+    Ok((toml_config, is_hybrid_vsock))
+}
+
+// Load the configuration the test vm boots with: the kata configuration
+// along with the hypervisor one, with the per run settings applied, and
+// whether the hypervisor uses hybrid vsock.
+pub(crate) fn load_vm_config(
+    name: &str,
+    vm_config: &TestVmConfig,
+) -> Result<(TomlConfig, HypervisorConfig, bool)> {
+    let (toml_config, is_hybrid_vsock) =
+        load_hypervisor_config(name, vm_config.config_path.as_deref())?;
+
+    let mut hypervisor_config = toml_config
         .hypervisor
         .get(name)
         .ok_or_else(|| anyhow!("Failed to get hypervisor config"))
-        .context("get hypervisor config")?;
+        .context("get hypervisor config")?
+        .clone();
+
+    apply_vm_config(name, &mut hypervisor_config, vm_config).context("apply test vm settings")?;
+
+    Ok((toml_config, hypervisor_config, is_hybrid_vsock))
+}
+
+// Port the agent ttrpc server listens on in the guest, as configured for
+// the runtime agent, or the default one.
+fn agent_vsock_port(toml_config: &TomlConfig) -> u32 {
+    toml_config
+        .agent
+        .get(&toml_config.runtime.agent_name)
+        .map(|agent| agent.server_port)
+        .filter(|port| *port != 0)
+        .unwrap_or(DEFAULT_AGENT_VSOCK_PORT)
+}
+
+// Boot the test vm.
+// In summary, this method
+// - parses hypervisor specific kata config file
+// - loads hypervisor specific config
+// - instantiates a hypervisor object
+// - calls prepare_vm
+// - instantiates device manager to handle devices
+// - calls start_vm to boot pod vm
+// - retrieves the agent ttrpc server socket address
+//
+// The boot is aborted once the cancellation token is triggered, in which
+// case the steps done so far are undone before returning.
+pub(crate) async fn boot_vm(
+    name: &str,
+    vm_config: &TestVmConfig,
+    cancel: &CancellationToken,
+) -> Result<TestVm, VmError> {
+    let PreparedVm {
+        hypervisor,
+        devices,
+        is_hybrid_vsock,
+        agent_port,
+        mut timings,
+    } = prepare_boot(name, vm_config, cancel).await?;
+
+    // start vm, the timeout being in milliseconds
+    let start_timeout = vm_config
+        .boot_timeout
+        .map(|t| i32::try_from(t.as_millis()).unwrap_or(i32::MAX))
+        .unwrap_or(VM_START_TIMEOUT);
+
+    let started = Instant::now();
+    if let Err(e) = cancellable(cancel, hypervisor.start_vm(start_timeout)).await {
+        // the hypervisor connecting to the virtiofs daemon may be what failed
+        let e = match devices.share_fs.log_path.as_str() {
+            "" => anyhow!("start_vm error: {:?}", e),
+            log_path => anyhow!(
+                "start_vm error: {:?}, virtiofsd output in {}:\n{}",
+                e,
+                log_path,
+                share_fs_utils::log_tail(log_path)
+            ),
+        };
+        abort_boot(&hypervisor, devices).await;
+        return Err(step_error(cancel, VmError::StartVm, e));
+    }
+
+    // the vm is running from here, so it has to be torn down on failure
+    let agent_socket_addr = match hypervisor.get_agent_socket().await {
+        Ok(addr) if !addr.is_empty() => addr,
+        Ok(addr) if vm_config.keep_without_agent => {
+            warn!(sl!(), "test vm booted without an agent socket, keeping it running";
+                "hypervisor" => name);
+            addr
+        }
+        result => {
+            abort_boot(&hypervisor, devices).await;
+            return Err(VmError::StartVm(match result {
+                Err(e) => e.context("get agent socket path"),
+                Ok(_) => anyhow!("hypervisor returned an empty agent socket address"),
+            }));
+        }
+    };
+    timings.start = started.elapsed();
+
+    log_boot_timings(name, &timings);
+
+    // return the vm structure
+    Ok(TestVm {
+        hypervisor_name: name.to_string(),
+        hypervisor_instance: hypervisor,
+        socket_addr: agent_socket_addr,
+        hybrid_vsock: is_hybrid_vsock,
+        agent_port,
+        share_fs: devices.share_fs,
+        rootfs_share_pid: devices.rootfs_pid,
+        extra_share_pids: devices.extra_share_pids,
+        extra_share_storages: devices.extra_share_storages,
+        scratch_disks: devices.scratch_disks,
+        iso_storages: devices.iso_storages,
+        pmem_storage: devices.pmem_storage,
+        created_tap: devices.created_tap,
+        vfio_devices: devices.vfio_devices,
+        device_manager: devices.dev_manager,
+        block_device_ids: devices.block_device_ids,
+        stop_timeout: vm_config.stop_timeout,
+        stop_kill: vm_config.stop_kill,
+        boot_timings: timings,
+        stopped: Arc::new(AtomicBool::new(false)),
+    })
+}
+
+fn log_boot_timings(name: &str, timings: &BootTimings) {
+    let devices_ms = timings
+        .devices
+        .iter()
+        .map(|(group, time)| format!("{}={}", group, time.as_millis()))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    info!(sl!(), "test vm boot timings";
+        "hypervisor" => name,
+        "config_load_ms" => timings.config_load.as_millis() as u64,
+        "prepare_ms" => timings.prepare.as_millis() as u64,
+        "devices_ms" => devices_ms,
+        "share_fs_ms" => timings.share_fs.as_millis() as u64,
+        "start_ms" => timings.start.as_millis() as u64);
+}
+
+// Hypervisor prepared to boot the test vm, along with the devices attached
+// to it
+struct PreparedVm {
+    hypervisor: Arc<dyn Hypervisor>,
+    devices: BootDevices,
+    is_hybrid_vsock: bool,
+    agent_port: u32,
+    timings: BootTimings,
+}
+
+// Run the boot steps preceding start_vm: load and apply the configuration,
+// prepare the vm and attach its devices.
+async fn prepare_boot(
+    name: &str,
+    vm_config: &TestVmConfig,
+    cancel: &CancellationToken,
+) -> Result<PreparedVm, VmError> {
+    let mut timings = BootTimings::default();
+
+    let started = Instant::now();
+    let (toml_config, hypervisor_config, is_hybrid_vsock) =
+        load_vm_config(name, vm_config).map_err(VmError::ConfigLoad)?;
+    timings.config_load = started.elapsed();
 
     let hypervisor: Arc<dyn Hypervisor> = match name {
         #[cfg(all(
@@ -100,6 +391,25 @@ pub(crate) async fn boot_vm(name: &str) -> Result<TestVm> {
                 .await;
             hyp_ch
         }
+        #[cfg(all(
+            feature = "dragonball",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        HYPERVISOR_NAME_DRAGONBALL => {
+            let hyp_db = Arc::new(Dragonball::new());
+            hyp_db
+                .set_hypervisor_config(hypervisor_config.clone())
+                .await;
+            hyp_db
+        }
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        HYPERVISOR_NAME_FIRECRACKER => {
+            let hyp_fc = Arc::new(Firecracker::new());
+            hyp_fc
+                .set_hypervisor_config(hypervisor_config.clone())
+                .await;
+            hyp_fc
+        }
         &_ => {
             let hyp_qemu = Arc::new(Qemu::new());
             hyp_qemu
@@ -112,89 +422,1561 @@ pub(crate) async fn boot_vm(name: &str) -> Result<TestVm> {
     // prepare vm
     // we do not pass any network namesapce since we dont want any
     let empty_anno_map: HashMap<String, String> = HashMap::new();
-    hypervisor
-        .prepare_vm(VM_NAME, None, &empty_anno_map, None)
-        .await
-        .context(" prepare test vm")?;
+    let started = Instant::now();
+    cancellable(
+        cancel,
+        hypervisor.prepare_vm(VM_NAME, None, &empty_anno_map, None),
+    )
+    .await
+    .context(" prepare test vm")
+    .map_err(|e| step_error(cancel, VmError::PrepareVm, e))?;
+    timings.prepare = started.elapsed();
+
+    let devices = attach_devices(
+        name,
+        &toml_config,
+        &hypervisor,
+        &hypervisor_config,
+        vm_config,
+        cancel,
+        &mut timings,
+    )
+    .await?;
+
+    Ok(PreparedVm {
+        hypervisor,
+        devices,
+        is_hybrid_vsock,
+        agent_port: agent_vsock_port(&toml_config),
+        timings,
+    })
+}
+
+// Dry run of the test vm boot: validate the configuration, devices and
+// file shares by running the boot up to start_vm, then undo it all.
+pub(crate) async fn check_boot(
+    name: &str,
+    vm_config: &TestVmConfig,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let prepared = prepare_boot(name, vm_config, cancel).await?;
+
+    release_devices(prepared.devices, "prepare only").await;
+
+    Ok(())
+}
+
+// Filesystems shared with and disks attached to the booting vm
+struct BootDevices {
+    share_fs: SharedFs,
+    // virtiofs daemon serving the guest rootfs, if booting from a directory
+    rootfs_pid: u32,
+    extra_share_pids: Vec<u32>,
+    extra_share_storages: Vec<Storage>,
+    scratch_disks: Vec<ScratchDisk>,
+    iso_storages: Vec<Storage>,
+    pmem_storage: Option<Storage>,
+    // tap device created for the vm network, removed along with the vm
+    created_tap: String,
+    vfio_devices: Vec<VfioPassthrough>,
+    dev_manager: Arc<RwLock<DeviceManager>>,
+    // hotplugged disks to unplug before stopping the vm
+    block_device_ids: Vec<String>,
+}
+
+// Instantiate the device manager, add the devices needed to boot the vm
+// and setup the filesystem sharing.
+async fn attach_devices(
+    name: &str,
+    toml_config: &TomlConfig,
+    hypervisor: &Arc<dyn Hypervisor>,
+    hypervisor_config: &HypervisorConfig,
+    vm_config: &TestVmConfig,
+    cancel: &CancellationToken,
+    timings: &mut BootTimings,
+) -> Result<BootDevices, VmError> {
+    let started = Instant::now();
 
     // instantiate device manager
-    let topo_config = TopologyConfigInfo::new(&toml_config);
+    let topo_config = TopologyConfigInfo::new(toml_config);
     let dev_manager = Arc::new(RwLock::new(
         DeviceManager::new(hypervisor.clone(), topo_config.as_ref())
             .await
-            .context("failed to create device manager")?,
+            .context("failed to create device manager")
+            .map_err(VmError::DeviceHotplug)?,
     ));
 
     // For qemu, we need some additional device handling
     // - vsock device
     // - block device for rootfs if using image
+    // - virtio-fs share for rootfs if using a directory
+    let mut rootfs_pid = 0;
     if name.contains(HYPERVISOR_NAME_QEMU) {
         add_vsock_device(dev_manager.clone())
             .await
-            .context("qemu::adding vsock device")?;
+            .context("qemu::adding vsock device")
+            .map_err(VmError::DeviceHotplug)?;
+
+        if !vm_config.rootfs_dir.is_empty() {
+            rootfs_pid = share_fs_utils::setup_rootfs_share(
+                hypervisor.clone(),
+                dev_manager.clone(),
+                &vm_config.rootfs_dir,
+                name,
+            )
+            .await
+            .context("qemu: share rootfs")
+            .map_err(VmError::SharedFs)?;
+        } else if !hypervisor_config.boot_info.image.is_empty() {
+            add_block_device(dev_manager.clone(), rootfs_image_config(hypervisor_config))
+                .await
+                .context("qemu: handle rootfs")
+                .map_err(VmError::DeviceHotplug)?;
+        }
+    }
+
+    // Dragonball runs in-process and is handed all of its devices, the
+    // hybrid vsock one included
+    #[cfg(all(
+        feature = "dragonball",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    if name == HYPERVISOR_NAME_DRAGONBALL {
+        add_hybrid_vsock_device(dev_manager.clone())
+            .await
+            .context("dragonball::adding hybrid vsock device")
+            .map_err(VmError::DeviceHotplug)?;
 
         if !hypervisor_config.boot_info.image.is_empty() {
-            let blk_config = BlockConfig {
-                path_on_host: hypervisor_config.boot_info.image.clone(),
-                is_readonly: true,
-                driver_option: hypervisor_config.boot_info.vm_rootfs_driver.clone(),
-                ..Default::default()
-            };
-            add_block_device(dev_manager.clone(), blk_config)
+            add_block_device(dev_manager.clone(), rootfs_image_config(hypervisor_config))
                 .await
-                .context("qemu: handle rootfs")?;
+                .context("dragonball: handle rootfs")
+                .map_err(VmError::DeviceHotplug)?;
         }
     }
 
-    // setup filesystem sharing using virtio-fs
-    let fs_info =
-        share_fs_utils::setup_virtio_fs(hypervisor.clone(), dev_manager.clone(), name).await?;
+    // Firecracker inserts the rootfs drive itself, only the hybrid vsock
+    // device is needed. It does not share filesystems, which the virtio-fs
+    // setup below skips based on the hypervisor capabilities.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if name == HYPERVISOR_NAME_FIRECRACKER {
+        add_hybrid_vsock_device(dev_manager.clone())
+            .await
+            .context("firecracker::adding hybrid vsock device")
+            .map_err(VmError::DeviceHotplug)?;
+    }
+    timings
+        .devices
+        .push(("boot".to_string(), started.elapsed()));
+
+    let mut scratch_disks = Vec::new();
+    let mut iso_storages = Vec::new();
+    let mut pmem_storage = None;
+    let mut created_tap = String::new();
+    let mut vfio_devices = Vec::new();
+    let mut extra_share_pids = Vec::new();
+    let mut extra_share_storages = Vec::new();
+    // the nvdimm is left out, it cannot be unplugged
+    let mut block_device_ids = Vec::new();
+    let block_driver = &hypervisor_config.blockdev_info.block_device_driver;
+
+    let attached: Result<()> = async {
+        let started = Instant::now();
+        attach_network_device(&dev_manager, hypervisor_config, vm_config, &mut created_tap).await?;
+        timings
+            .devices
+            .push(("network".to_string(), started.elapsed()));
+
+        let started = Instant::now();
+        attach_scratch_disks(
+            &dev_manager,
+            block_driver,
+            vm_config,
+            &mut scratch_disks,
+            &mut block_device_ids,
+        )
+        .await?;
+        timings
+            .devices
+            .push(("scratch".to_string(), started.elapsed()));
+
+        let started = Instant::now();
+        attach_iso_images(
+            &dev_manager,
+            block_driver,
+            vm_config,
+            &mut iso_storages,
+            &mut block_device_ids,
+        )
+        .await?;
+        timings.devices.push(("iso".to_string(), started.elapsed()));
+
+        let started = Instant::now();
+        attach_pmem_image(&dev_manager, vm_config, &mut pmem_storage).await?;
+        timings
+            .devices
+            .push(("pmem".to_string(), started.elapsed()));
+
+        let started = Instant::now();
+        attach_vfio_devices(&dev_manager, vm_config, &mut vfio_devices).await?;
+        timings
+            .devices
+            .push(("vfio".to_string(), started.elapsed()));
+
+        let started = Instant::now();
+        attach_virtiofs_shares(
+            name,
+            hypervisor,
+            &dev_manager,
+            vm_config,
+            &mut extra_share_pids,
+            &mut extra_share_storages,
+        )
+        .await?;
+        timings
+            .devices
+            .push(("virtiofs".to_string(), started.elapsed()));
 
-    // start vm
-    if let Err(e) = hypervisor.start_vm(VM_START_TIMEOUT).await {
-        // shutdown the virtiofs daemon
-        let _ = share_fs_utils::shutdown_virtiofsd(fs_info).await;
-        return Err(anyhow!("start_vm error: {:?}", e));
+        Ok(())
     }
+    .await;
 
-    let agent_socket_addr = hypervisor
-        .get_agent_socket()
+    let started = Instant::now();
+    let share_fs = match attached {
+        Err(e) => Err(VmError::DeviceHotplug(e)),
+        // Do not spawn the virtiofs daemon for a boot that is going away
+        Ok(_) if cancel.is_cancelled() => Err(VmError::Cancelled),
+        // no filesystem sharing without shared memory
+        Ok(_) if vm_config.mem_shared == Some(false) => Ok(SharedFs::default()),
+        // setup filesystem sharing using virtio-fs
+        Ok(_) => share_fs_utils::setup_virtio_fs(hypervisor.clone(), dev_manager.clone(), name)
+            .await
+            .map_err(VmError::SharedFs),
+    };
+    timings.share_fs = started.elapsed();
+
+    match share_fs {
+        Ok(share_fs) => Ok(BootDevices {
+            share_fs,
+            rootfs_pid,
+            extra_share_pids,
+            extra_share_storages,
+            scratch_disks,
+            iso_storages,
+            pmem_storage,
+            created_tap,
+            vfio_devices,
+            dev_manager,
+            block_device_ids,
+        }),
+        Err(e) => {
+            // undo the attaches done so far, unplugging the disks before
+            // their backing loop devices go away
+            remove_block_devices(&dev_manager, &block_device_ids).await;
+            let _ = share_fs_utils::kill_virtiofsd(rootfs_pid);
+            for pid in &extra_share_pids {
+                let _ = share_fs_utils::kill_virtiofsd(*pid);
+            }
+            let _ = vm_utils::release_scratch_disks(&scratch_disks);
+            let _ = vm_utils::delete_tap_device(&created_tap);
+            Err(e)
+        }
+    }
+}
+
+// Share the requested host directories over virtio-fs devices of their
+// own, recording each daemon as soon as it has been started so that it gets
+// killed should a later share fail.
+async fn attach_virtiofs_shares(
+    name: &str,
+    hypervisor: &Arc<dyn Hypervisor>,
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    vm_config: &TestVmConfig,
+    share_pids: &mut Vec<u32>,
+    share_storages: &mut Vec<Storage>,
+) -> Result<()> {
+    for share in &vm_config.virtiofs_shares {
+        let pid = share_fs_utils::setup_extra_share(
+            hypervisor.clone(),
+            dev_mgr.clone(),
+            &share.host_path,
+            &share.tag,
+            name,
+        )
+        .await?;
+        share_pids.push(pid);
+        share_storages.push(vm_utils::get_virtiofs_share_storage(share));
+    }
+
+    Ok(())
+}
+
+// Attach the requested scratch disks. Formatting their images being the
+// slow part, the images are created concurrently and the disks then
+// attached in order, each one being recorded as soon as it has been
+// attached so that it gets released should a later one fail.
+async fn attach_scratch_disks(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    block_driver: &str,
+    vm_config: &TestVmConfig,
+    scratch_disks: &mut Vec<ScratchDisk>,
+    device_ids: &mut Vec<String>,
+) -> Result<()> {
+    let jobs = match vm_config.attach_jobs {
+        0 => DEFAULT_ATTACH_JOBS,
+        jobs => jobs,
+    };
+    let images = create_scratch_images(&vm_config.scratch_disks, jobs)?;
+
+    let mut result = Ok(());
+    let mut unattached = Vec::new();
+
+    for (index, (disk_config, disk)) in vm_config.scratch_disks.iter().zip(images).enumerate() {
+        if result.is_err() {
+            unattached.push(disk);
+            continue;
+        }
+
+        match attach_scratch_disk(dev_mgr, block_driver, disk_config, disk, index).await {
+            Ok((disk, device_id)) => {
+                scratch_disks.push(disk);
+                device_ids.push(device_id);
+            }
+            Err(e) => result = Err(e.context(format!("scratch disk {index}"))),
+        }
+    }
+
+    // the attached disks are released by the caller
+    let _ = vm_utils::release_scratch_disks(&unattached);
+
+    result
+}
+
+// Create the images of the scratch disks, up to jobs at a time, returning
+// them in order. Should one fail, the images created are released.
+fn create_scratch_images(configs: &[ScratchDiskConfig], jobs: usize) -> Result<Vec<ScratchDisk>> {
+    let indexed: Vec<(usize, &ScratchDiskConfig)> = configs.iter().enumerate().collect();
+    let mut images = Vec::with_capacity(configs.len());
+
+    for chunk in indexed.chunks(jobs) {
+        let results: Vec<Result<ScratchDisk>> = std::thread::scope(|s| {
+            let handles: Vec<_> = chunk
+                .iter()
+                .map(|(index, c)| {
+                    s.spawn(move || {
+                        vm_utils::create_scratch_image(c.size_mb, &c.fstype, *index)
+                            .with_context(|| format!("scratch disk {index}"))
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|h| {
+                    h.join()
+                        .unwrap_or_else(|_| Err(anyhow!("scratch image creation panicked")))
+                })
+                .collect()
+        });
+
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(image) => images.push(image),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        if let Some(e) = error {
+            let _ = vm_utils::release_scratch_disks(&images);
+            return Err(e);
+        }
+    }
+
+    Ok(images)
+}
+
+// Attach a freshly formatted disk backed by a sparse image and a loop
+// device to the vm. The returned disk holds the storage for the agent to
+// mount it in the guest, and is returned along with its device id. The
+// disk is attached with its own block driver if it has one, and is
+// released should the attach fail.
+async fn attach_scratch_disk(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    block_driver: &str,
+    disk_config: &ScratchDiskConfig,
+    mut disk: ScratchDisk,
+    index: usize,
+) -> Result<(ScratchDisk, String)> {
+    let blk_config = BlockConfig {
+        path_on_host: disk.loop_device.clone(),
+        is_readonly: disk_config.readonly,
+        driver_option: disk_config
+            .block_driver
+            .as_deref()
+            .unwrap_or(block_driver)
+            .to_string(),
+        ..Default::default()
+    };
+
+    let attached = match attach_disk(dev_mgr, blk_config).await {
+        Ok(attached) => attached,
+        Err(e) => {
+            let _ = vm_utils::release_scratch_disks(&[disk]);
+            return Err(e);
+        }
+    };
+
+    disk.storage =
+        vm_utils::get_scratch_storage(&attached.driver, attached.source, disk_config, index);
+
+    Ok((disk, attached.device_id))
+}
+
+// Attach the requested ISO9660 images read-only, returning the storages for
+// the agent to mount them in the guest. Though a CD-ROM would be the natural
+// device model, the hypervisor crate only attaches disks.
+async fn attach_iso_images(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    block_driver: &str,
+    vm_config: &TestVmConfig,
+    iso_storages: &mut Vec<Storage>,
+    device_ids: &mut Vec<String>,
+) -> Result<()> {
+    for (index, image) in vm_config.iso_images.iter().enumerate() {
+        let blk_config = BlockConfig {
+            path_on_host: image.clone(),
+            is_readonly: true,
+            driver_option: block_driver.to_string(),
+            ..Default::default()
+        };
+
+        let attached = attach_disk(dev_mgr, blk_config)
+            .await
+            .with_context(|| format!("ISO image {image}"))?;
+
+        iso_storages.push(vm_utils::get_iso_storage(
+            &attached.driver,
+            attached.source,
+            index,
+        ));
+        device_ids.push(attached.device_id);
+    }
+
+    Ok(())
+}
+
+// Attach the requested filesystem image as an nvdimm device, returning the
+// storage for the agent to mount it in the guest. Qemu only cold plugs
+// nvdimm devices, which it does for those attached before the vm starts.
+async fn attach_pmem_image(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    vm_config: &TestVmConfig,
+    pmem_storage: &mut Option<Storage>,
+) -> Result<()> {
+    let pmem_config = match &vm_config.pmem_image {
+        Some(pmem_config) => pmem_config,
+        None => return Ok(()),
+    };
+
+    let blk_config = BlockConfig {
+        path_on_host: pmem_config.path.clone(),
+        driver_option: VIRTIO_PMEM.to_string(),
+        ..Default::default()
+    };
+
+    let attached = attach_disk(dev_mgr, blk_config)
         .await
-        .context("get agent socket path")?;
+        .with_context(|| format!("pmem image {}", pmem_config.path))?;
 
-    // return the vm structure
-    Ok(TestVm {
-        hypervisor_name: name.to_string(),
-        hypervisor_instance: hypervisor,
-        socket_addr: agent_socket_addr,
-        hybrid_vsock: is_hybrid_vsock,
-        share_fs: fs_info,
+    *pmem_storage = Some(vm_utils::get_pmem_storage(
+        &attached.driver,
+        attached.source,
+        pmem_config,
+    ));
+
+    Ok(())
+}
+
+// Attach a network device backed by the requested tap device, creating
+// the tap if it does not exist yet. The name of a created tap is recorded
+// for it to be removed along with the vm.
+async fn attach_network_device(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    hypervisor_config: &HypervisorConfig,
+    vm_config: &TestVmConfig,
+    created_tap: &mut String,
+) -> Result<()> {
+    let tap = &vm_config.tap_device;
+    if tap.is_empty() {
+        return Ok(());
+    }
+
+    // qemu opens the tap with one queue per pair of the virtio-net device
+    let multi_queue = hypervisor_config.network_info.network_queues > 1;
+    if vm_utils::create_tap_device(tap, multi_queue)? {
+        *created_tap = tap.clone();
+    }
+
+    add_network_device(dev_mgr.clone(), tap)
+        .await
+        .with_context(|| format!("network device on tap {tap}"))
+}
+
+// Pass the requested host PCI devices through to the vm, recording the
+// guest PCI path each one got
+async fn attach_vfio_devices(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    vm_config: &TestVmConfig,
+    vfio_devices: &mut Vec<VfioPassthrough>,
+) -> Result<()> {
+    for bdf in &vm_config.vfio_devices {
+        let guest_pci_path = handle_vfio_device(dev_mgr, bdf)
+            .await
+            .with_context(|| format!("vfio device {bdf}"))?;
+
+        vfio_devices.push(VfioPassthrough {
+            bdf: bdf.clone(),
+            guest_pci_path,
+        });
+    }
+
+    Ok(())
+}
+
+// Attach the VFIO device at the host PCI address, which must be bound to
+// vfio-pci, returning its guest PCI path. The path is empty if left for
+// the hypervisor to assign.
+async fn handle_vfio_device(dev_mgr: &Arc<RwLock<DeviceManager>>, bdf: &str) -> Result<String> {
+    // the device is passed through along with its iommu group
+    let host_path = get_vfio_device(bdf.to_string()).context("get vfio iommu group")?;
+
+    let vfio_config = VfioConfig {
+        host_path,
+        dev_type: "c".to_string(),
+        bus_type: "pci".to_string(),
+        hostdev_prefix: VFIO_HOSTDEV_PREFIX.to_string(),
+        ..Default::default()
+    };
+
+    match handle_device(dev_mgr, &DeviceConfig::VfioCfg(vfio_config))
+        .await
+        .context("attach vfio device")?
+    {
+        DeviceType::Vfio(device) => Ok(device
+            .devices
+            .first()
+            .and_then(|dev| dev.guest_pci_path.as_ref())
+            .map(|path| path.to_string())
+            .unwrap_or_default()),
+        device => Err(anyhow!("unexpected vfio device: {:?}", device)),
+    }
+}
+
+// Disk attached to the vm, with the storage driver and the guest address
+// of the disk for that driver
+struct AttachedDisk {
+    device_id: String,
+    driver: String,
+    source: String,
+}
+
+// Attach a disk to the vm
+async fn attach_disk(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    blk_config: BlockConfig,
+) -> Result<AttachedDisk> {
+    let (device_id, config) = match handle_device(dev_mgr, &DeviceConfig::BlockCfg(blk_config))
+        .await
+        .context("attach disk")?
+    {
+        DeviceType::Block(device) => (device.device_id, device.config),
+        device => return Err(anyhow!("unexpected disk device: {:?}", device)),
+    };
+
+    // the device manager normalizes the driver to the agent storage one
+    let source = match config.driver_option.as_str() {
+        DRIVER_BLK_PCI_TYPE => config.pci_path.map(|p| p.to_string()),
+        DRIVER_SCSI_TYPE => config.scsi_addr,
+        DRIVER_BLK_CCW_TYPE => config.ccw_addr,
+        _ => Some(config.virt_path),
+    };
+
+    let source = match source {
+        Some(source) => source,
+        None => {
+            // the disk is attached but useless, do not leave it behind
+            remove_block_devices(dev_mgr, &[device_id]).await;
+
+            return Err(anyhow!(
+                "no guest address for disk with driver {}",
+                config.driver_option
+            ));
+        }
+    };
+
+    Ok(AttachedDisk {
+        device_id,
+        driver: config.driver_option,
+        source,
     })
 }
 
+// Run a boot step, giving up on it once the boot is cancelled
+async fn cancellable<T>(
+    cancel: &CancellationToken,
+    step: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        result = step => result,
+        _ = cancel.cancelled() => Err(anyhow!(ERR_BOOT_CANCELLED)),
+    }
+}
+
+// The error of a failed boot step, which is the cancellation if the step
+// got interrupted by it
+fn step_error(
+    cancel: &CancellationToken,
+    step: fn(anyhow::Error) -> VmError,
+    e: anyhow::Error,
+) -> VmError {
+    if cancel.is_cancelled() {
+        VmError::Cancelled
+    } else {
+        step(e)
+    }
+}
+
+// Undo a boot which failed or got cancelled once the devices got attached:
+// shutdown the virtiofs daemon and stop the vm if it got launched
+async fn abort_boot(hypervisor: &Arc<dyn Hypervisor>, devices: BootDevices) {
+    if let Err(e) = hypervisor.stop_vm().await {
+        warn!(sl!(), "abort boot: failed to stop vm: {:?}", e);
+    }
+
+    release_devices(devices, "abort boot").await;
+}
+
+// Stop the file share daemons and release the disks of a vm which is not
+// running. Failures are only logged, prefixed with the step undone.
+async fn release_devices(devices: BootDevices, step: &str) {
+    remove_block_devices(&devices.dev_manager, &devices.block_device_ids).await;
+
+    if let Err(e) = share_fs_utils::shutdown_virtiofsd(devices.share_fs).await {
+        warn!(sl!(), "{}: failed to shutdown virtiofsd: {:?}", step, e);
+    }
+
+    if let Err(e) = share_fs_utils::kill_virtiofsd(devices.rootfs_pid) {
+        warn!(sl!(), "{}: failed to kill rootfs virtiofsd: {:?}", step, e);
+    }
+
+    for pid in &devices.extra_share_pids {
+        if let Err(e) = share_fs_utils::kill_virtiofsd(*pid) {
+            warn!(sl!(), "{}: failed to kill share virtiofsd: {:?}", step, e);
+        }
+    }
+
+    if let Err(e) = vm_utils::release_scratch_disks(&devices.scratch_disks) {
+        warn!(sl!(), "{}: failed to release scratch disks: {:?}", step, e);
+    }
+
+    if let Err(e) = vm_utils::delete_tap_device(&devices.created_tap) {
+        warn!(sl!(), "{}: failed to delete tap device: {:?}", step, e);
+    }
+}
+
+// Whether the hypervisor runs within the tool process rather than as a
+// daemon, in which case there is no hypervisor process to wait for or kill
+// and the vm does not outlive the tool
+pub(crate) fn is_in_process(name: &str) -> bool {
+    name == HYPERVISOR_NAME_DRAGONBALL
+}
+
+// Stop the vm and release what it was given. Once the vm of an instance or
+// its clones is gone, the next stops do nothing. A stop failing before that
+// leaves the instance to be stopped again, not to leak the vm.
 pub(crate) async fn stop_vm(instance: TestVm) -> Result<()> {
-    share_fs_utils::shutdown_virtiofsd(instance.share_fs).await?;
+    if instance.stopped.swap(true, Ordering::SeqCst) {
+        info!(sl!(), "test vm already stopped");
+        return Ok(());
+    }
+
+    if let Err(e) = shutdown_vm(&instance).await {
+        instance.stopped.store(false, Ordering::SeqCst);
+        return Err(e);
+    }
+
+    release_host_resources(&instance)
+}
+
+// Stop the vm along with the virtiofs daemon of its shared path and wait
+// for the hypervisor to exit
+async fn shutdown_vm(instance: &TestVm) -> Result<()> {
+    let stop_timeout = if is_in_process(&instance.hypervisor_name) {
+        None
+    } else {
+        instance.stop_timeout
+    };
+
+    // the pid is gone with the hypervisor state once stopped
+    let hypervisor_pid = match stop_timeout {
+        Some(_) => instance
+            .hypervisor_instance
+            .get_vmm_master_tid()
+            .await
+            .context("get hypervisor pid")?,
+        None => 0,
+    };
+
+    remove_block_devices(&instance.device_manager, &instance.block_device_ids).await;
+
+    // container rootfs shares left behind by interrupted commands, which the
+    // shared path must not be removed through
+    if !instance.share_fs.shared_path.is_empty() {
+        vm_utils::umount_all_under(&instance.share_fs.shared_path)
+            .context("unmount shared path")?;
+    }
+
+    share_fs_utils::shutdown_virtiofsd(instance.share_fs.clone()).await?;
     instance
         .hypervisor_instance
         .stop_vm()
         .await
-        .context("stopping pod vm")
+        .context("stopping pod vm")?;
+
+    if let Some(timeout) = stop_timeout {
+        wait_hypervisor_exit(
+            hypervisor_pid,
+            &instance.socket_addr,
+            timeout,
+            instance.stop_kill,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Release the guest rootfs, disks and network of a vm which is gone. Each
+// of them is released even if another one fails, the first failure being
+// returned.
+fn release_host_resources(instance: &TestVm) -> Result<()> {
+    let mut results = vec![share_fs_utils::kill_virtiofsd(instance.rootfs_share_pid)
+        .context("stopping rootfs virtiofsd")];
+    for pid in &instance.extra_share_pids {
+        results.push(share_fs_utils::kill_virtiofsd(*pid).context("stopping share virtiofsd"));
+    }
+    results.push(
+        vm_utils::release_scratch_disks(&instance.scratch_disks).context("release scratch disks"),
+    );
+    results.push(vm_utils::delete_tap_device(&instance.created_tap).context("delete tap device"));
+
+    let mut errors = results.into_iter().filter_map(|r| r.err());
+    let first = errors.next();
+    for e in errors {
+        warn!(sl!(), "failed to release the test vm resources: {:?}", e);
+    }
+
+    first.map_or(Ok(()), Err)
+}
+
+// Unplug the disks hotplugged to the running vm, in reverse order, so that
+// their device slots get released rather than left for the vm teardown.
+// Failures, devices already gone included, are only logged since the vm
+// gets stopped next anyway.
+async fn remove_block_devices(dev_mgr: &Arc<RwLock<DeviceManager>>, device_ids: &[String]) {
+    if device_ids.is_empty() {
+        return;
+    }
+
+    let mut dev_mgr = match tokio::time::timeout(DEV_MGR_LOCK_TIMEOUT, dev_mgr.write()).await {
+        Ok(guard) => guard,
+        Err(_) => {
+            warn!(sl!(), "device manager busy, not removing the block devices");
+            return;
+        }
+    };
+
+    for device_id in device_ids.iter().rev() {
+        if let Err(e) = dev_mgr.try_remove_device(device_id).await {
+            warn!(
+                sl!(),
+                "failed to remove block device {}: {:?}", device_id, e
+            );
+        }
+    }
+}
+
+// Wait for the hypervisor process to exit after the vm was stopped, so that
+// a vm booted next does not collide with its leftovers. The process is
+// killed once the timeout expires if requested, and a hybrid vsock socket
+// left behind is removed.
+async fn wait_hypervisor_exit(
+    pid: u32,
+    socket_addr: &str,
+    timeout: Duration,
+    kill: bool,
+) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    while !vm_utils::process_exited(pid) {
+        if tokio::time::Instant::now() >= deadline {
+            if !kill {
+                return Err(anyhow!(
+                    "hypervisor pid {} did not exit within {:?}",
+                    pid,
+                    timeout
+                ));
+            }
+
+            warn!(sl!(), "hypervisor pid {} did not exit, killing it", pid);
+
+            let pid = nix::unistd::Pid::from_raw(pid as i32);
+            if let Err(err) = nix::sys::signal::kill(pid, nix::sys::signal::SIGKILL) {
+                if err != nix::Error::ESRCH {
+                    return Err(anyhow!("failed to kill hypervisor pid {} {}", pid, err));
+                }
+            }
+            break;
+        }
+
+        tokio::time::sleep(HYPERVISOR_EXIT_INTERVAL).await;
+    }
+
+    if let Some(path) = socket_addr.strip_prefix(HYBRID_VSOCK_PREFIX) {
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(anyhow!("failed to remove stale socket {}: {}", path, e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Tear down a test vm booted by another process, given its saved state.
+// The hypervisor is killed rather than asked to shut down since there is
+// no hypervisor instance to drive it, and the mounts left in the shared
+// path are removed before the path itself.
+pub(crate) async fn teardown_vm(state: &TestVmState) -> Result<()> {
+    let hypervisor_pid = recorded_pid(state, state.hypervisor_pid);
+    if hypervisor_pid != 0 {
+        let pid = nix::unistd::Pid::from_raw(hypervisor_pid as i32);
+
+        if let Err(err) = nix::sys::signal::kill(pid, nix::sys::signal::SIGKILL) {
+            if err != nix::Error::ESRCH {
+                return Err(anyhow!("failed to kill hypervisor pid {} {}", pid, err));
+            }
+        }
+    }
+
+    share_fs_utils::kill_virtiofsd(recorded_pid(state, state.rootfs_share_pid))
+        .context("kill rootfs virtiofsd")?;
+    for pid in &state.extra_share_pids {
+        share_fs_utils::kill_virtiofsd(recorded_pid(state, *pid))
+            .context("kill share virtiofsd")?;
+    }
+    vm_utils::release_scratch_disks(&state.scratch_disks).context("release scratch disks")?;
+    vm_utils::delete_tap_device(&state.created_tap).context("delete tap device")?;
+
+    if !state.shared_path.is_empty() {
+        vm_utils::umount_all_under(&state.shared_path).context("unmount shared path")?;
+    }
+
+    share_fs_utils::shutdown_virtiofsd(SharedFs {
+        pid: recorded_pid(state, state.virtiofsd_pid),
+        shared_path: state.shared_path.clone(),
+        ..Default::default()
+    })
+    .await
+}
+
+// The pid of a process of the saved state if it still is that process, 0
+// otherwise. The state file may be stale, the pid having been reused by an
+// unrelated process since, which must not get killed.
+fn recorded_pid(state: &TestVmState, pid: u32) -> u32 {
+    if pid == 0 {
+        return 0;
+    }
+
+    match state.process_start_times.get(&pid) {
+        Some(start_time) if vm_utils::process_start_time(pid) == Some(*start_time) => pid,
+        Some(_) => {
+            info!(
+                sl!(),
+                "pid {} of the test vm state is gone, not killing it", pid
+            );
+            0
+        }
+        None => {
+            warn!(sl!(), "no start time saved for pid {}, not killing it", pid);
+            0
+        }
+    }
+}
+
+// Pause the vcpus of the booted vm. The virtiofsd daemons are left
+// running, so the shares are served again as soon as the vm resumes.
+pub(crate) async fn pause_vm(instance: &TestVm) -> Result<()> {
+    check_pause_support(&instance.hypervisor_name)?;
+
+    instance
+        .hypervisor_instance
+        .pause_vm()
+        .await
+        .context("pausing the test vm")
+}
+
+// Resume the vcpus of a paused vm
+pub(crate) async fn resume_vm(instance: &TestVm) -> Result<()> {
+    check_pause_support(&instance.hypervisor_name)?;
+
+    instance
+        .hypervisor_instance
+        .resume_vm()
+        .await
+        .context("resuming the test vm")
+}
+
+fn check_pause_support(name: &str) -> Result<()> {
+    if !PAUSE_HYPERVISORS.contains(&name) {
+        return Err(anyhow!("pausing the vm is not supported with {}", name));
+    }
+
+    Ok(())
+}
+
+// Hotplug or unplug vcpus for the running vm to have COUNT of them, from the
+// configured number it booted with. Returns the resulting number of vcpus.
+pub(crate) async fn resize_vcpus(instance: &TestVm, count: u32) -> Result<u32> {
+    check_resize_support(&instance.hypervisor_name)?;
+
+    let cpu_info = instance
+        .hypervisor_instance
+        .hypervisor_config()
+        .await
+        .cpu_info;
+    if count > cpu_info.default_maxvcpus {
+        return Err(anyhow!(
+            "{} vcpus exceed the configured maximum of {}",
+            count,
+            cpu_info.default_maxvcpus
+        ));
+    }
+
+    let (_, new_vcpus) = instance
+        .hypervisor_instance
+        .resize_vcpu(cpu_info.default_vcpus.ceil() as u32, count)
+        .await
+        .context("resizing the test vm vcpus")?;
+
+    Ok(new_vcpus)
+}
+
+// Hotplug memory for the running vm to have SIZE_MB megabytes of it in
+// total. Returns the resulting memory size in megabytes.
+pub(crate) async fn resize_memory(instance: &TestVm, size_mb: u32) -> Result<u32> {
+    check_resize_support(&instance.hypervisor_name)?;
+
+    // no maximum means up to the host memory, which the hypervisor checks
+    let memory_info = instance
+        .hypervisor_instance
+        .hypervisor_config()
+        .await
+        .memory_info;
+    if memory_info.default_maxmemory > 0 && size_mb > memory_info.default_maxmemory {
+        return Err(anyhow!(
+            "{} MiB of memory exceed the configured maximum of {} MiB",
+            size_mb,
+            memory_info.default_maxmemory
+        ));
+    }
+
+    let (new_mem_mb, _) = instance
+        .hypervisor_instance
+        .resize_memory(size_mb)
+        .await
+        .context("resizing the test vm memory")?;
+
+    Ok(new_mem_mb)
+}
+
+fn check_resize_support(name: &str) -> Result<()> {
+    if !RESIZE_HYPERVISORS.contains(&name) {
+        return Err(anyhow!("resizing the vm is not supported with {}", name));
+    }
+
+    Ok(())
+}
+
+// Get the kernel command line of the booted vm. Qemu is handed the
+// complete command line as an argument, which is read back from its
+// process. Other hypervisors receive it over their API, in which case only
+// the configured parameters are known, without the rootfs and debug ones
+// added by the hypervisor.
+pub(crate) async fn kernel_cmdline(instance: &TestVm) -> Result<String> {
+    let hypervisor = &instance.hypervisor_instance;
+
+    if instance.hypervisor_name == HYPERVISOR_NAME_QEMU {
+        let pid = hypervisor.get_vmm_master_tid().await?;
+        let cmdline = std::fs::read(format!("/proc/{pid}/cmdline"))
+            .with_context(|| format!("read qemu {pid} command line"))?;
+
+        let args: Vec<String> = cmdline
+            .split(|b| *b == 0)
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect();
+
+        return args
+            .iter()
+            .position(|arg| arg == "-append")
+            .and_then(|pos| args.get(pos + 1))
+            .cloned()
+            .ok_or_else(|| anyhow!("no kernel command line passed to qemu"));
+    }
+
+    warn!(
+        sl!(),
+        "{} kernel command line lacks the parameters added by the hypervisor",
+        instance.hypervisor_name
+    );
+
+    Ok(hypervisor.hypervisor_config().await.boot_info.kernel_params)
+}
+
+// Query the hypervisor for the device models and features in use. The
+// hypervisor config reflects the effective settings after any fallback
+// applied while the vm was prepared and started.
+pub(crate) async fn negotiated_features(instance: &TestVm) -> Result<NegotiatedFeatures> {
+    let hypervisor = &instance.hypervisor_instance;
+    let config = hypervisor.hypervisor_config().await;
+    let capabilities = hypervisor.capabilities().await?;
+
+    // fs sharing is only in use if the share got set up
+    let shared_fs = if !instance.share_fs.shared_path.is_empty() {
+        config.shared_fs.shared_fs.clone().unwrap_or_default()
+    } else {
+        String::from("none")
+    };
+
+    let vsock = if instance.hybrid_vsock && capabilities.is_hybrid_vsock_supported() {
+        "hybrid-vsock"
+    } else {
+        "vsock"
+    };
+
+    let io_threads = if config.enable_iothreads {
+        config.indep_iothreads
+    } else {
+        0
+    };
+
+    Ok(NegotiatedFeatures {
+        block_driver: config.blockdev_info.block_device_driver.clone(),
+        rootfs_driver: config.boot_info.vm_rootfs_driver.clone(),
+        shared_fs,
+        vsock: vsock.to_string(),
+        io_threads,
+    })
+}
+
+// Apply the per run test vm settings on top of the hypervisor config
+fn apply_vm_config(
+    name: &str,
+    hypervisor_config: &mut HypervisorConfig,
+    vm_config: &TestVmConfig,
+) -> Result<()> {
+    if vm_config.io_threads > 0 {
+        if name != HYPERVISOR_NAME_QEMU {
+            return Err(anyhow!("io threads are only supported with qemu"));
+        }
+
+        let vcpus = hypervisor_config.cpu_info.default_vcpus.ceil() as u32;
+        if vm_config.io_threads > vcpus {
+            return Err(anyhow!(
+                "io threads ({}) exceed the number of vcpus ({})",
+                vm_config.io_threads,
+                vcpus
+            ));
+        }
+
+        // virtio-blk-pci devices get attached to the independent iothreads
+        hypervisor_config.enable_iothreads = true;
+        hypervisor_config.indep_iothreads = vm_config.io_threads;
+    }
+
+    if vm_config.mem_prealloc {
+        hypervisor_config.memory_info.enable_mem_prealloc = true;
+    }
+
+    // only dragonball backs the guest memory from the configured hugepages
+    if vm_config.hugepages {
+        if name != HYPERVISOR_NAME_DRAGONBALL {
+            return Err(anyhow!("hugepages are only supported with dragonball"));
+        }
+
+        let memory_info = &mut hypervisor_config.memory_info;
+        vm_utils::validate_hugepages(&memory_info.hugepage_type, memory_info.default_memory)?;
+        memory_info.enable_hugepages = true;
+    }
+
+    let virtio_fs_options =
+        vm_config.virtio_fs_cache.is_some() || vm_config.virtio_fs_dax_window.is_some();
+    if virtio_fs_options && hypervisor_config.shared_fs.shared_fs.is_none() {
+        return Err(anyhow!("virtio-fs options require a virtio-fs share"));
+    }
+
+    if let Some(cache) = &vm_config.virtio_fs_cache {
+        hypervisor_config.shared_fs.virtio_fs_cache = cache.clone();
+    }
+
+    // only the dragonball virtio-fs device sizes its DAX window from the
+    // configuration
+    if let Some(window) = vm_config.virtio_fs_dax_window {
+        if name != HYPERVISOR_NAME_DRAGONBALL {
+            return Err(anyhow!(
+                "virtio-fs DAX windows are only supported with dragonball"
+            ));
+        }
+
+        hypervisor_config.shared_fs.virtio_fs_is_dax = true;
+        hypervisor_config.shared_fs.virtio_fs_cache_size = u32::try_from(window >> 20)
+            .map_err(|_| anyhow!("virtio-fs DAX window is too large"))?;
+    }
+
+    // The guest memory gets shared with the host as soon as a virtio-fs
+    // device is attached, so unshared memory means no filesystem sharing.
+    if vm_config.mem_shared == Some(false) {
+        if name != HYPERVISOR_NAME_QEMU {
+            return Err(anyhow!("{} always shares the guest memory", name));
+        }
+
+        if hypervisor_config.shared_fs.virtio_fs_is_dax {
+            return Err(anyhow!("virtio-fs DAX requires shared guest memory"));
+        }
+
+        if !vm_config.rootfs_dir.is_empty() {
+            return Err(anyhow!(
+                "booting from a rootfs directory requires shared guest memory"
+            ));
+        }
+
+        if !vm_config.virtiofs_shares.is_empty() {
+            return Err(anyhow!("virtio-fs shares require shared guest memory"));
+        }
+    }
+
+    if !vm_config.virtiofs_shares.is_empty() {
+        if !VIRTIO_FS_SHARE_HYPERVISORS.contains(&name) {
+            return Err(anyhow!("virtio-fs shares are not supported with {}", name));
+        }
+
+        vm_utils::validate_virtiofs_shares(&vm_config.virtiofs_shares)?;
+    }
+
+    for image in &vm_config.iso_images {
+        vm_utils::validate_iso9660(image)?;
+    }
+
+    if let Some(pmem_config) = &vm_config.pmem_image {
+        if name != HYPERVISOR_NAME_QEMU {
+            return Err(anyhow!("pmem images are only supported with qemu"));
+        }
+
+        // the nvdimm is plugged as a memory device
+        if hypervisor_config.memory_info.memory_slots == 0 {
+            return Err(anyhow!("pmem images require memory slots to be configured"));
+        }
+
+        vm_utils::validate_pmem_image(&pmem_config.path)?;
+    }
+
+    for (index, disk_config) in vm_config.scratch_disks.iter().enumerate() {
+        if let Some(driver) = &disk_config.block_driver {
+            let drivers = scratch_block_drivers(name);
+            if !drivers.contains(&driver.as_str()) {
+                return Err(anyhow!(
+                    "unsupported block driver {:?} for scratch disk {} with {}, expected one of {:?}",
+                    driver,
+                    index,
+                    name,
+                    drivers
+                ));
+            }
+        }
+    }
+
+    if !vm_config.tap_device.is_empty() {
+        if !TAP_HYPERVISORS.contains(&name) {
+            return Err(anyhow!(
+                "tap network devices are not supported with {}",
+                name
+            ));
+        }
+
+        vm_utils::validate_tap_name(&vm_config.tap_device)?;
+    }
+
+    if !vm_config.vfio_devices.is_empty() && !VFIO_HYPERVISORS.contains(&name) {
+        return Err(anyhow!("vfio devices are not supported with {}", name));
+    }
+
+    for bdf in &vm_config.vfio_devices {
+        vm_utils::validate_vfio_device(bdf)?;
+    }
+
+    if !vm_config.rootfs_dir.is_empty() {
+        if name != HYPERVISOR_NAME_QEMU {
+            return Err(anyhow!(
+                "booting from a rootfs directory is only supported with qemu"
+            ));
+        }
+
+        vm_utils::validate_rootfs_dir(&vm_config.rootfs_dir)?;
+        vm_utils::check_source_fs(&vm_config.rootfs_dir)?;
+
+        // Boot without image or initrd, the parameters overriding the
+        // block rootfs ones generated by the hypervisor.
+        hypervisor_config.boot_info.image.clear();
+        hypervisor_config.boot_info.initrd.clear();
+        hypervisor_config.boot_info.add_kernel_params(vec![
+            format!("root={}", share_fs_utils::ROOTFS_GUEST_TAG),
+            String::from("rootfstype=virtiofs"),
+            String::from("rootflags=rw"),
+            String::from("rw"),
+        ]);
+    }
+
+    Ok(())
+}
+
+// Wrapper around do_handle_device_within which bounds the time spent
+// waiting for the device manager lock, so that a contended lock results in
+// a "device manager busy" error rather than hanging indefinitely.
+pub(crate) async fn handle_device(
+    dev_mgr: &Arc<RwLock<DeviceManager>>,
+    cfg: &DeviceConfig,
+) -> Result<DeviceType> {
+    do_handle_device_within(dev_mgr, cfg, DEV_MGR_LOCK_TIMEOUT).await
+}
+
+// Read-only block device backing the guest rootfs with the configured image
+fn rootfs_image_config(hypervisor_config: &HypervisorConfig) -> BlockConfig {
+    BlockConfig {
+        path_on_host: hypervisor_config.boot_info.image.clone(),
+        is_readonly: true,
+        driver_option: hypervisor_config.boot_info.vm_rootfs_driver.clone(),
+        ..Default::default()
+    }
 }
 
 async fn add_block_device(dev_mgr: Arc<RwLock<DeviceManager>>, cfg: BlockConfig) -> Result<()> {
-    do_handle_device(&dev_mgr, &DeviceConfig::BlockCfg(cfg))
+    handle_device(&dev_mgr, &DeviceConfig::BlockCfg(cfg))
         .await
         .context("handle block device failed")?;
     Ok(())
 }
 
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+async fn add_hybrid_vsock_device(dev_mgr: Arc<RwLock<DeviceManager>>) -> Result<()> {
+    let hvsock_config = HybridVsockConfig {
+        guest_cid: DEFAULT_GUEST_VSOCK_CID,
+        uds_path: get_hvsock_path(VM_NAME),
+    };
+
+    handle_device(&dev_mgr, &DeviceConfig::HybridVsockCfg(hvsock_config))
+        .await
+        .context("handle hybrid vsock device failed")?;
+    Ok(())
+}
+
+async fn add_network_device(dev_mgr: Arc<RwLock<DeviceManager>>, tap: &str) -> Result<()> {
+    let network_config = NetworkConfig {
+        host_dev_name: tap.to_string(),
+        virt_iface_name: tap.to_string(),
+        guest_mac: Some(Address(vm_utils::random_mac_address())),
+        ..Default::default()
+    };
+
+    handle_device(&dev_mgr, &DeviceConfig::NetworkCfg(network_config))
+        .await
+        .context("handle network device failed")?;
+    Ok(())
+}
+
 async fn add_vsock_device(dev_mgr: Arc<RwLock<DeviceManager>>) -> Result<()> {
     let vsock_config = VsockConfig {
         guest_cid: libc::VMADDR_CID_ANY,
     };
 
-    do_handle_device(&dev_mgr, &DeviceConfig::VsockCfg(vsock_config))
+    handle_device(&dev_mgr, &DeviceConfig::VsockCfg(vsock_config))
         .await
         .context("handle vsock device failed")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::mock_hypervisor::MockHypervisor;
+
+    async fn mock_device_manager(mock: &Arc<MockHypervisor>) -> Result<Arc<RwLock<DeviceManager>>> {
+        let hypervisor: Arc<dyn Hypervisor> = mock.clone();
+
+        Ok(Arc::new(RwLock::new(
+            DeviceManager::new(hypervisor, None)
+                .await
+                .context("device manager")?,
+        )))
+    }
+
+    fn block_config() -> HypervisorConfig {
+        let mut config = HypervisorConfig::default();
+        config.blockdev_info.block_device_driver = VIRTIO_BLOCK_PCI.to_string();
+        config
+    }
+
+    #[tokio::test]
+    async fn test_attach_scratch_disk() {
+        let mock = Arc::new(MockHypervisor::new(block_config()));
+        let dev_mgr = mock_device_manager(&mock).await.unwrap();
+
+        let disk_config = ScratchDiskConfig {
+            size_mb: 16,
+            fstype: String::from("ext4"),
+            readonly: true,
+            block_driver: None,
+        };
+
+        for (i, expected) in ["02", "03"].iter().enumerate() {
+            let disk = ScratchDisk {
+                image: format!("/tmp/scratch-{i}.img"),
+                loop_device: format!("/dev/loop-mock{i}"),
+                ..Default::default()
+            };
+
+            let (disk, device_id) =
+                attach_scratch_disk(&dev_mgr, VIRTIO_BLOCK_PCI, &disk_config, disk, i)
+                    .await
+                    .unwrap();
+
+            let storage = &disk.storage;
+            assert!(!device_id.is_empty(), "test[{}]", i);
+            assert_eq!(storage.driver, DRIVER_BLK_PCI_TYPE, "test[{}]", i);
+            assert_eq!(storage.source, *expected, "test[{}]", i);
+            assert_eq!(storage.fstype, "ext4", "test[{}]", i);
+            assert_eq!(storage.options, vec!["ro"], "test[{}]", i);
+            assert_eq!(
+                storage.mount_point,
+                format!("/run/kata-containers/scratch/{i}"),
+                "test[{}]",
+                i
+            );
+        }
+
+        assert_eq!(mock.added().len(), 2);
+        assert!(mock.removed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_attach_disk_without_address() {
+        let mock = Arc::new(MockHypervisor::without_disk_address(block_config()));
+        let dev_mgr = mock_device_manager(&mock).await.unwrap();
+
+        let blk_config = BlockConfig {
+            path_on_host: String::from("/dev/loop-mock"),
+            driver_option: VIRTIO_BLOCK_PCI.to_string(),
+            ..Default::default()
+        };
+
+        let err = attach_disk(&dev_mgr, blk_config).await.unwrap_err();
+        assert!(err.to_string().contains("no guest address"), "{:?}", err);
+
+        // the useless disk got unplugged
+        let removed = mock.removed();
+        assert_eq!(removed.len(), 1);
+        assert!(
+            matches!(&removed[0], DeviceType::Block(blk) if blk.config.path_on_host == "/dev/loop-mock"),
+            "{:?}",
+            removed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_device_busy() {
+        let mock = Arc::new(MockHypervisor::new(block_config()));
+        let dev_mgr = mock_device_manager(&mock).await.unwrap();
+
+        let blk_config = BlockConfig {
+            path_on_host: String::from("/dev/loop-mock"),
+            driver_option: VIRTIO_BLOCK_PCI.to_string(),
+            ..Default::default()
+        };
+        let cfg = DeviceConfig::BlockCfg(blk_config);
+
+        // someone else holding the lock
+        let guard = dev_mgr.write().await;
+        let err = do_handle_device_within(&dev_mgr, &cfg, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("device manager busy"), "{:?}", err);
+        assert!(mock.added().is_empty());
+
+        // and goes through once released
+        drop(guard);
+        do_handle_device_within(&dev_mgr, &cfg, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(mock.added().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_attach_devices_rollback() {
+        let mock = Arc::new(MockHypervisor::failing_add_after(block_config(), 1));
+        let hypervisor: Arc<dyn Hypervisor> = mock.clone();
+
+        // the second image fails to be hotplugged
+        let vm_config = TestVmConfig {
+            iso_images: vec![
+                String::from("/tmp/agent-ctl-mock-0.iso"),
+                String::from("/tmp/agent-ctl-mock-1.iso"),
+            ],
+            ..Default::default()
+        };
+
+        let result = attach_devices(
+            "mock",
+            &TomlConfig::default(),
+            &hypervisor,
+            &block_config(),
+            &vm_config,
+            &CancellationToken::new(),
+            &mut BootTimings::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(VmError::DeviceHotplug(_))));
+
+        // the first one got unplugged again
+        let added = mock.added();
+        assert_eq!(added.len(), 1);
+        let removed = mock.removed();
+        assert_eq!(removed.len(), 1);
+        assert!(
+            matches!(&removed[0], DeviceType::Block(blk) if blk.config.path_on_host == "/tmp/agent-ctl-mock-0.iso"),
+            "{:?}",
+            removed
+        );
+    }
+
+    async fn mock_test_vm(mock: &Arc<MockHypervisor>) -> TestVm {
+        let dev_mgr = mock_device_manager(mock).await.unwrap();
+
+        TestVm {
+            hypervisor_name: HYPERVISOR_NAME_QEMU.to_string(),
+            hypervisor_instance: mock.clone(),
+            socket_addr: mock.get_agent_socket().await.unwrap(),
+            hybrid_vsock: false,
+            agent_port: DEFAULT_AGENT_VSOCK_PORT,
+            share_fs: SharedFs::default(),
+            rootfs_share_pid: 0,
+            extra_share_pids: Vec::new(),
+            extra_share_storages: Vec::new(),
+            scratch_disks: Vec::new(),
+            iso_storages: Vec::new(),
+            pmem_storage: None,
+            created_tap: String::new(),
+            vfio_devices: Vec::new(),
+            device_manager: dev_mgr,
+            block_device_ids: Vec::new(),
+            stop_timeout: None,
+            stop_kill: false,
+            boot_timings: BootTimings::default(),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_vm_twice() {
+        let mock = Arc::new(MockHypervisor::new(block_config()));
+        let instance = mock_test_vm(&mock).await;
+
+        // say once from an error path and once from the normal flow
+        stop_vm(instance.clone()).await.unwrap();
+        stop_vm(instance).await.unwrap();
+
+        assert_eq!(mock.stop_calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stop_vm_retry() {
+        let mock = Arc::new(MockHypervisor::new(block_config()));
+        let instance = mock_test_vm(&mock).await;
+
+        mock.fail_next_stop();
+        assert!(stop_vm(instance.clone()).await.is_err());
+
+        // the vm is still there to be stopped
+        stop_vm(instance.clone()).await.unwrap();
+        stop_vm(instance).await.unwrap();
+
+        assert_eq!(mock.stop_calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_setup_inline_virtio_fs() {
+        let mut config = block_config();
+        config.shared_fs.shared_fs = Some(share_fs_utils::INLINE_VIRTIO_FS.to_string());
+
+        let mock = Arc::new(MockHypervisor::new(config));
+        let hypervisor: Arc<dyn Hypervisor> = mock.clone();
+        let dev_mgr = mock_device_manager(&mock).await.unwrap();
+
+        // the shared path is created under the fixed root, removed along
+        // with the guard should an assert fail
+        let root = tempfile::Builder::new()
+            .prefix("agent-ctl-mock-")
+            .tempdir_in(share_fs_utils::VIRTIO_FS_ROOT_PATH)
+            .unwrap();
+        let root_path = root.path().file_name().unwrap().to_str().unwrap();
+
+        let share_fs = share_fs_utils::setup_virtio_fs(hypervisor, dev_mgr, root_path)
+            .await
+            .unwrap();
+
+        // served by the hypervisor, without a daemon
+        assert_eq!(share_fs.pid, 0);
+        assert!(share_fs.log_path.is_empty());
+        assert_eq!(share_fs.shared_path, root.path().to_str().unwrap());
+
+        let added = mock.added();
+        assert_eq!(added.len(), 1);
+        match &added[0] {
+            DeviceType::ShareFs(dev) => {
+                assert_eq!(dev.config.mount_tag, share_fs_utils::MOUNT_GUEST_TAG);
+                assert_eq!(dev.config.fs_type, share_fs_utils::INLINE_VIRTIO_FS);
+            }
+            device => panic!("unexpected device: {:?}", device),
+        }
+
+        // the passthrough fs gets mounted through a device update
+        let updated = mock.updated();
+        assert_eq!(updated.len(), 1);
+        match &updated[0] {
+            DeviceType::ShareFs(dev) => {
+                let mount_config = dev.config.mount_config.as_ref().unwrap();
+                assert_eq!(mount_config.source, share_fs.shared_path);
+                assert_eq!(
+                    mount_config.mount_point,
+                    share_fs_utils::INLINE_PASSTHROUGH_MOUNT
+                );
+            }
+            device => panic!("unexpected device: {:?}", device),
+        }
+
+        let storage = vm_utils::get_shared_fs_storage(&share_fs.backend, None).unwrap();
+        assert_eq!(storage.source, share_fs_utils::MOUNT_GUEST_TAG);
+        assert_eq!(storage.options, vec!["nodev"]);
+    }
+}
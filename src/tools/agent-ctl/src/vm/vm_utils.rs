@@ -4,20 +4,130 @@
 //
 // Description: Boot UVM for testing container storages/volumes.
 
-use crate::vm::share_fs_utils;
+use crate::vm::{
+    share_fs_utils, PmemImageConfig, ScratchDisk, ScratchDiskConfig, TmpfsConfig,
+    VirtioFsShareConfig,
+};
 use anyhow::{anyhow, Context, Result};
 use kata_sys_util::mount;
+use kata_types::config::hypervisor::HugePageType;
 use kata_types::config::TomlConfig;
+use kata_types::device::{DRIVER_EPHEMERAL_TYPE, DRIVER_VIRTIOFS_TYPE};
 use nix::mount::MsFlags;
 use protocols::agent::Storage;
-use slog::info;
+use rand::RngExt;
+use slog::{info, warn};
+use std::ffi::CString;
 use std::fs;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// Guest directory the guest paths below are relative to, unless another
+// one is set for an agent using a relocated base
+const DEFAULT_GUEST_BASE_PATH: &str = "/run/kata-containers";
 
 // constants for container rootfs share
-const GUEST_SHARED_PATH: &str = "/run/kata-containers/shared/containers";
+const GUEST_SHARED_PATH: &str = "shared/containers";
+// Guest mount point of an inline virtio-fs share, whose passthrough mount
+// then shows up at GUEST_SHARED_PATH
+const GUEST_INLINE_SHARED_PATH: &str = "shared";
 const ROOTFS: &str = "rootfs";
 const VIRTIO_SHARE_FS_TYPE: &str = "virtiofs";
 
+// Size of the tag field in the virtio-fs device configuration
+const MAX_VIRTIO_FS_TAG_LEN: usize = 36;
+
+// Guest storage driver, filesystem type, mount options and default mount
+// point, relative to the guest base directory, of the file share for each
+// sharing backend of the hypervisor configuration
+#[allow(clippy::type_complexity)]
+const SHARE_FS_STORAGE_TYPES: &[(&str, &str, &str, &[&str], &str)] = &[
+    (
+        share_fs_utils::VIRTIO_FS,
+        DRIVER_VIRTIOFS_TYPE,
+        VIRTIO_SHARE_FS_TYPE,
+        &["nodev"],
+        GUEST_SHARED_PATH,
+    ),
+    (
+        share_fs_utils::INLINE_VIRTIO_FS,
+        DRIVER_VIRTIOFS_TYPE,
+        VIRTIO_SHARE_FS_TYPE,
+        &["nodev"],
+        GUEST_INLINE_SHARED_PATH,
+    ),
+];
+
+const PROC_MOUNTINFO: &str = "/proc/self/mountinfo";
+const PROC_MOUNTS: &str = "/proc/mounts";
+
+// Host filesystems on which bind mounting or hotplugging a storage source
+// can behave unexpectedly: network filesystems and overlay
+const UNRELIABLE_SOURCE_FS_TYPES: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "ceph",
+    "glusterfs",
+    "fuse.sshfs",
+    "9p",
+    "overlay",
+];
+
+// Fail rather than warn when a storage source is on an unreliable filesystem
+static STRICT_SOURCE_FS: AtomicBool = AtomicBool::new(false);
+
+// File created in a shared container rootfs to check xattr passthrough
+const XATTR_TEST_FILE: &str = ".agent-ctl-xattr";
+
+// Marker created at the root of its shared rootfs by a started container
+const READY_MARKER_FILE: &str = ".agent-ctl-ready";
+
+// Guest directories the scratch disks, ISO images and pmem image get
+// mounted under
+const GUEST_SCRATCH_PATH: &str = "scratch";
+const GUEST_ISO_PATH: &str = "iso";
+const GUEST_PMEM_PATH: &str = "pmem";
+const GUEST_EPHEMERAL_PATH: &str = "ephemeral";
+const TMPFS_FS_TYPE: &str = "tmpfs";
+
+// ISO9660 primary volume descriptor identifier, found right after the type
+// byte of the first volume descriptor at sector 16
+const ISO9660_MAGIC: &[u8] = b"CD001";
+const ISO9660_MAGIC_OFFSET: u64 = 16 * 2048 + 1;
+const ISO9660_FS_TYPE: &str = "iso9660";
+
+// Host network interfaces and the longest name they can have
+const SYS_CLASS_NET: &str = "/sys/class/net";
+const MAX_IFACE_NAME_LEN: usize = 15;
+
+// Host PCI devices and the driver they must be bound to for VFIO
+const SYS_BUS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+const VFIO_PCI_DRIVER: &str = "vfio-pci";
+
+// Host hugepage pool and transparent hugepage setting
+const PROC_MEMINFO: &str = "/proc/meminfo";
+const SYS_THP_ENABLED: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
+
+// A directory booted as the guest rootfs must have one of these init paths
+const ROOTFS_INIT_PATHS: &[&str] = &["sbin/init", "init", "usr/lib/systemd/systemd"];
+
+lazy_static! {
+    // Temporary files and directories created by the tool during a session
+    static ref TEMP_PATHS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+    // Loop devices set up by the tool and not detached yet
+    static ref LOOP_DEVICES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    // Guest directory the storages and shares are placed under
+    static ref GUEST_BASE_PATH: Mutex<String> = Mutex::new(DEFAULT_GUEST_BASE_PATH.to_string());
+}
+
 // Helper function to parse a configuration file.
 pub fn load_config(config_file: &str) -> Result<TomlConfig> {
     info!(sl!(), "Load kata configuration file {}", config_file);
@@ -40,6 +150,17 @@ pub fn to_kernel_string(key: String, val: String) -> Result<String> {
         Err(anyhow!("Empty key and value"))
     } else if key.is_empty() {
         Err(anyhow!("Empty key"))
+    } else if key.contains(|c: char| c.is_whitespace() || c == '=') {
+        Err(anyhow!(
+            "Invalid key {:?}: must not contain whitespace or '='",
+            key
+        ))
+    } else if val.contains(char::is_whitespace) {
+        Err(anyhow!(
+            "Invalid value {:?} for key {}: must not contain whitespace",
+            val,
+            key
+        ))
     } else if val.is_empty() {
         Ok(key.to_string())
     } else {
@@ -47,19 +168,245 @@ pub fn to_kernel_string(key: String, val: String) -> Result<String> {
     }
 }
 
-pub fn get_virtiofs_storage() -> Storage {
-    Storage {
-        driver: String::from(share_fs_utils::VIRTIO_FS),
+// Record a temporary file or directory created by the tool, so that it
+// can be removed when the session is torn down.
+pub fn track_temp_path<P: AsRef<Path>>(path: P) {
+    let path = path.as_ref().to_path_buf();
+    let mut paths = TEMP_PATHS.lock().unwrap();
+
+    if !paths.contains(&path) {
+        paths.push(path);
+    }
+}
+
+// Remove all the temporary paths recorded during the session.
+pub fn cleanup_temp_paths() {
+    let paths: Vec<PathBuf> = TEMP_PATHS.lock().unwrap().drain(..).collect();
+
+    for path in paths.iter().rev() {
+        let result = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+
+        match result {
+            Ok(_) => info!(sl!(), "removed temporary path {:?}", path),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!(sl!(), "failed to remove temporary path {:?}: {}", path, e),
+        }
+    }
+}
+
+// Run the binary with `--version` and return the parsed version.
+pub fn get_binary_version(path: &str) -> Result<String> {
+    let output = Command::new(path)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("failed to execute {path} --version"))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} --version failed: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(parse_version(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// Extract the version number from the first line of a `--version` output
+// (ex: "QEMU emulator version 8.2.0" or "cloud-hypervisor v38.0.0"),
+// falling back to the whole line if no version number is found.
+fn parse_version(output: &str) -> String {
+    let line = output.lines().next().unwrap_or_default().trim();
+
+    line.split_whitespace()
+        .map(|w| w.trim_start_matches('v'))
+        .find(|w| w.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap_or(line)
+        .to_string()
+}
+
+// Storage mounting the host/guest file share, with the guest driver,
+// filesystem type and options matching the sharing backend in use. The
+// share is mounted at the guest path, the backend default if not set.
+pub fn get_shared_fs_storage(backend: &str, guest_path: Option<&str>) -> Result<Storage> {
+    let (_, driver, fstype, options, default_path) = SHARE_FS_STORAGE_TYPES
+        .iter()
+        .find(|(name, _, _, _, _)| *name == backend)
+        .ok_or_else(|| anyhow!("unsupported file sharing backend: {:?}", backend))?;
+
+    Ok(Storage {
+        driver: driver.to_string(),
         driver_options: Vec::new(),
         source: String::from(share_fs_utils::MOUNT_GUEST_TAG),
-        fstype: String::from(VIRTIO_SHARE_FS_TYPE),
+        fstype: fstype.to_string(),
+        options: options.iter().map(|o| o.to_string()).collect(),
+        mount_point: guest_path
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| guest_path_of(default_path)),
+        ..Default::default()
+    })
+}
+
+// Storage mounting an extra virtio-fs share at its guest path
+pub fn get_virtiofs_share_storage(share: &VirtioFsShareConfig) -> Storage {
+    Storage {
+        driver: DRIVER_VIRTIOFS_TYPE.to_string(),
+        source: share.tag.clone(),
+        fstype: VIRTIO_SHARE_FS_TYPE.to_string(),
         options: vec![String::from("nodev")],
-        mount_point: String::from(GUEST_SHARED_PATH),
+        mount_point: share.guest_path.clone(),
+        ..Default::default()
+    }
+}
+
+// Check the extra virtio-fs shares: existing host directories, absolute
+// guest paths, and distinct mount tags fitting in a virtio-fs tag, the ones
+// of the main and rootfs shares being taken
+pub fn validate_virtiofs_shares(shares: &[VirtioFsShareConfig]) -> Result<()> {
+    let mut tags = vec![
+        share_fs_utils::MOUNT_GUEST_TAG,
+        share_fs_utils::ROOTFS_GUEST_TAG,
+    ];
+
+    for share in shares {
+        if !Path::new(&share.host_path).is_dir() {
+            return Err(anyhow!(
+                "virtio-fs share {:?} is not a directory",
+                share.host_path
+            ));
+        }
+
+        if !Path::new(&share.guest_path).is_absolute() {
+            return Err(anyhow!(
+                "virtio-fs share guest path {:?} must be absolute",
+                share.guest_path
+            ));
+        }
+
+        if share.tag.is_empty() || share.tag.len() > MAX_VIRTIO_FS_TAG_LEN {
+            return Err(anyhow!(
+                "virtio-fs share tag {:?} must be 1 to {} bytes long",
+                share.tag,
+                MAX_VIRTIO_FS_TAG_LEN
+            ));
+        }
+
+        if tags.contains(&share.tag.as_str()) {
+            return Err(anyhow!("virtio-fs share tag {:?} is in use", share.tag));
+        }
+        tags.push(&share.tag);
+    }
+
+    Ok(())
+}
+
+// Storage mounting a scratch disk in the guest. The source is the guest
+// address of the disk for the block driver in use.
+pub fn get_scratch_storage(
+    driver: &str,
+    source: String,
+    disk_config: &ScratchDiskConfig,
+    index: usize,
+) -> Storage {
+    let options = if disk_config.readonly {
+        vec![String::from("ro")]
+    } else {
+        Vec::new()
+    };
+
+    Storage {
+        driver: driver.to_string(),
+        source,
+        fstype: disk_config.fstype.clone(),
+        options,
+        mount_point: guest_path_of(&format!("{}/{}", GUEST_SCRATCH_PATH, index)),
+        ..Default::default()
+    }
+}
+
+// Storage mounting a read-only ISO9660 image in the guest
+pub fn get_iso_storage(driver: &str, source: String, index: usize) -> Storage {
+    Storage {
+        driver: driver.to_string(),
+        source,
+        fstype: ISO9660_FS_TYPE.to_string(),
+        options: vec![String::from("ro")],
+        mount_point: guest_path_of(&format!("{}/{}", GUEST_ISO_PATH, index)),
+        ..Default::default()
+    }
+}
+
+// Storage mounting the pmem image in the guest. The source is the pmem
+// device the image shows up as (ex: /dev/pmem0).
+pub fn get_pmem_storage(driver: &str, source: String, pmem_config: &PmemImageConfig) -> Storage {
+    Storage {
+        driver: driver.to_string(),
+        source,
+        fstype: pmem_config.fstype.clone(),
+        mount_point: guest_path_of(GUEST_PMEM_PATH),
+        ..Default::default()
+    }
+}
+
+// Storage mounting a tmpfs in the guest, backed by no host device. Note
+// the agent mounts ephemeral storages with the tmpfs defaults, only
+// applying the fsGroup option.
+pub fn get_tmpfs_storage(tmpfs_config: &TmpfsConfig, index: usize) -> Storage {
+    let mut options = vec![format!("size={}", tmpfs_config.size)];
+    if let Some(mode) = tmpfs_config.mode {
+        options.push(format!("mode={mode:o}"));
+    }
+
+    Storage {
+        driver: DRIVER_EPHEMERAL_TYPE.to_string(),
+        source: TMPFS_FS_TYPE.to_string(),
+        fstype: TMPFS_FS_TYPE.to_string(),
+        options,
+        mount_point: guest_path_of(&format!("{}/{}", GUEST_EPHEMERAL_PATH, index)),
         ..Default::default()
     }
 }
 
-pub fn share_rootfs(bundle_dir: &str, host_path: &str, id: &str) -> Result<String> {
+// Check that the pmem image is a non-empty regular file
+pub fn validate_pmem_image(path: &str) -> Result<()> {
+    let metadata = fs::metadata(path).with_context(|| format!("stat pmem image {path}"))?;
+
+    if !metadata.is_file() {
+        return Err(anyhow!("pmem image {} is not a regular file", path));
+    }
+
+    if metadata.len() == 0 {
+        return Err(anyhow!("pmem image {} is empty", path));
+    }
+
+    Ok(())
+}
+
+// Check that the file is an ISO9660 image
+pub fn validate_iso9660(path: &str) -> Result<()> {
+    let mut file = fs::File::open(path).with_context(|| format!("open ISO image {path}"))?;
+    let mut magic = [0u8; 5];
+
+    file.seek(SeekFrom::Start(ISO9660_MAGIC_OFFSET))
+        .and_then(|_| file.read_exact(&mut magic))
+        .with_context(|| format!("read ISO image {path}"))?;
+
+    if magic != ISO9660_MAGIC {
+        return Err(anyhow!("{} is not an ISO9660 image", path));
+    }
+
+    Ok(())
+}
+
+// Bind mount the container rootfs in the shared directory, read-only if
+// requested, and return its guest path. The read-only bind mount is a
+// remount of the initial one, which makes the share read-only without
+// affecting the source.
+pub fn share_rootfs(bundle_dir: &str, host_path: &str, id: &str, readonly: bool) -> Result<String> {
     info!(sl!(), "share_rootfs");
 
     // prepare rootfs string on host
@@ -67,12 +414,13 @@ pub fn share_rootfs(bundle_dir: &str, host_path: &str, id: &str) -> Result<Strin
     info!(sl!(), "share_rootfs:: target: {}", rootfs_host_path);
 
     let rootfs_src_path = format!("{bundle_dir}/{ROOTFS}");
+    check_source_fs(&rootfs_src_path)?;
 
     // Mount the src path to shared path
     mount::bind_mount_unchecked(
         &rootfs_src_path,
         &rootfs_host_path,
-        false,
+        readonly,
         MsFlags::MS_SLAVE,
     )
     .with_context(|| {
@@ -83,13 +431,91 @@ pub fn share_rootfs(bundle_dir: &str, host_path: &str, id: &str) -> Result<Strin
     })?;
 
     // Return the guest equivalent path
-    let guest_rootfs_path = format!("{}/{}", String::from(GUEST_SHARED_PATH), id);
+    let guest_rootfs_path = guest_path_of(&format!("{}/{}", GUEST_SHARED_PATH, id));
 
     info!(sl!(), "share_rootfs:: guest path {}", guest_rootfs_path);
 
     Ok(guest_rootfs_path)
 }
 
+// Copy a host file into the shared directory and return its guest path
+pub fn share_file(src: &str, host_path: &str, id: &str, name: &str) -> Result<String> {
+    let file_name = format!("{id}-{name}");
+    let shared_file = Path::new(host_path).join(&file_name);
+
+    fs::copy(src, &shared_file)
+        .with_context(|| format!("share_file:: failed to copy {src} to {shared_file:?}"))?;
+    track_temp_path(&shared_file);
+
+    Ok(guest_path_of(&format!("{GUEST_SHARED_PATH}/{file_name}")))
+}
+
+// Host path of the marker a container creates at the root of its shared
+// rootfs once started
+pub fn ready_marker_host_path(host_path: &str, id: &str) -> PathBuf {
+    Path::new(&get_host_share_path(host_path, id)).join(READY_MARKER_FILE)
+}
+
+// Remove any marker left in the shared rootfs of the container, returning
+// the path of the marker inside the container
+pub fn reset_ready_marker(host_path: &str, id: &str) -> Result<String> {
+    let host_file = ready_marker_host_path(host_path, id);
+
+    match fs::remove_file(&host_file) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(anyhow!(
+                "failed to remove stale marker {:?}: {}",
+                host_file,
+                e
+            ))
+        }
+        _ => track_temp_path(&host_file),
+    }
+
+    Ok(format!("/{READY_MARKER_FILE}"))
+}
+
+// Create an empty file carrying the given xattr in the shared rootfs of
+// the container. Returns the host path of the file along with its path
+// inside the container.
+pub fn create_xattr_file(
+    host_path: &str,
+    id: &str,
+    name: &str,
+    value: &str,
+) -> Result<(PathBuf, String)> {
+    let file = Path::new(&get_host_share_path(host_path, id)).join(XATTR_TEST_FILE);
+
+    fs::write(&file, b"").with_context(|| format!("failed to create {file:?}"))?;
+    track_temp_path(&file);
+
+    let c_path = CString::new(file.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+
+    // SAFETY: both strings are NUL terminated and the value outlives the call
+    let ret = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        let _ = fs::remove_file(&file);
+        return Err(anyhow!(
+            "failed to set xattr {} on {:?}: {}",
+            name,
+            file,
+            err
+        ));
+    }
+
+    Ok((file, format!("/{XATTR_TEST_FILE}")))
+}
+
 pub fn unshare_rootfs(host_path: &str, id: &str) -> Result<()> {
     info!(sl!(), "unshare_rootfs");
 
@@ -106,22 +532,706 @@ pub fn unshare_rootfs(host_path: &str, id: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn set_strict_source_fs(strict: bool) {
+    STRICT_SOURCE_FS.store(strict, Ordering::Relaxed);
+}
+
+// Place the guest storages and shares under another guest directory, the
+// default one being kept for an empty path
+pub fn set_guest_base_path(path: &str) {
+    if !path.is_empty() {
+        *GUEST_BASE_PATH.lock().unwrap() = path.trim_end_matches('/').to_string();
+    }
+}
+
+// Guest path of a path relative to the guest base directory
+fn guest_path_of(path: &str) -> String {
+    format!("{}/{}", GUEST_BASE_PATH.lock().unwrap(), path)
+}
+
+// Decode the octal escapes (e.g. \040 for a space) used in /proc/mounts
+// and mountinfo
+pub fn unescape_mount_path(path: &str) -> String {
+    let mut result = String::new();
+    let mut rest = path;
+
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+
+        match rest.get(..3).and_then(|o| u8::from_str_radix(o, 8).ok()) {
+            Some(c) => {
+                result.push(c as char);
+                rest = &rest[3..];
+            }
+            None => result.push('\\'),
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+// Get the type of the host filesystem holding the path, i.e. the one
+// mounted on the longest mount point containing it
+fn get_host_fs_type(path: &Path) -> Result<String> {
+    let mounts = fs::read_to_string(PROC_MOUNTS).context("read mounts")?;
+
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, mount_point, fs_type, ..] => {
+                    Some((PathBuf::from(unescape_mount_path(mount_point)), *fs_type))
+                }
+                _ => None,
+            }
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        // the last mount wins when several are stacked on the same point
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|(_, fs_type)| fs_type.to_string())
+        .ok_or_else(|| anyhow!("no mount found for {:?}", path))
+}
+
+// Check whether a block or virtio-fs source lives on a network or overlay
+// host filesystem. This is only reported as a warning unless strict source
+// checking is enabled.
+pub fn check_source_fs(source: &str) -> Result<()> {
+    let path = fs::canonicalize(source).with_context(|| format!("resolve source {source}"))?;
+    let fs_type = get_host_fs_type(&path)?;
+
+    if !UNRELIABLE_SOURCE_FS_TYPES.contains(&fs_type.as_str()) {
+        return Ok(());
+    }
+
+    let msg = format!(
+        "storage source {} is on a {} filesystem, which can cause unexpected test failures",
+        source, fs_type
+    );
+
+    if STRICT_SOURCE_FS.load(Ordering::Relaxed) {
+        return Err(anyhow!(msg));
+    }
+
+    warn!(sl!(), "{}", msg);
+    Ok(())
+}
+
+// Run a host command, returning its trimmed stdout
+fn run_host_command(cmd: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to execute {cmd}"))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} {:?} failed: {}",
+            cmd,
+            args,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Allocate a sparse image of the given size, format it and set up a loop
+// device for it. The image is removed if any of the steps fails.
+pub fn create_scratch_image(size_mb: u64, fstype: &str, index: usize) -> Result<ScratchDisk> {
+    if size_mb == 0 {
+        return Err(anyhow!("scratch disk size must not be zero"));
+    }
+
+    let image = std::env::temp_dir()
+        .join(format!(
+            "agent-ctl-scratch-{}-{}.img",
+            std::process::id(),
+            index
+        ))
+        .to_string_lossy()
+        .to_string();
+
+    let file = fs::File::create(&image).with_context(|| format!("create scratch image {image}"))?;
+
+    let result = check_source_fs(&image)
+        .and_then(|_| {
+            file.set_len(size_mb * 1024 * 1024)
+                .context("allocate scratch image")
+        })
+        .and_then(|_| run_host_command(&format!("mkfs.{fstype}"), &[&image]))
+        .and_then(|_| run_host_command("losetup", &["--find", "--show", &image]));
+
+    match result {
+        Ok(loop_device) => {
+            info!(sl!(), "created scratch disk {} on {}", image, loop_device);
+            LOOP_DEVICES.lock().unwrap().push(loop_device.clone());
+            Ok(ScratchDisk {
+                image,
+                loop_device,
+                ..Default::default()
+            })
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&image);
+            Err(e)
+        }
+    }
+}
+
+// Detach the loop devices of the scratch disks and remove their images. A
+// loop device no longer backed by its image, detached already and possibly
+// reused since, is left alone.
+pub fn release_scratch_disks(disks: &[ScratchDisk]) -> Result<()> {
+    for disk in disks {
+        if !disk.loop_device.is_empty() {
+            let image =
+                fs::canonicalize(&disk.image).unwrap_or_else(|_| PathBuf::from(&disk.image));
+
+            match loop_backing_file(&disk.loop_device) {
+                Some(backing_file) if backing_file == image => {
+                    run_host_command("losetup", &["--detach", &disk.loop_device])?;
+                }
+                _ => warn!(
+                    sl!(),
+                    "loop device {} no longer backs {}, not detaching it",
+                    disk.loop_device,
+                    disk.image
+                ),
+            }
+
+            LOOP_DEVICES
+                .lock()
+                .unwrap()
+                .retain(|dev| *dev != disk.loop_device);
+        }
+
+        match fs::remove_file(&disk.image) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("failed to remove {}: {}", disk.image, e)),
+        }
+    }
+
+    Ok(())
+}
+
+// Backing file of the loop device, None if it is not attached
+fn loop_backing_file(loop_device: &str) -> Option<PathBuf> {
+    let name = Path::new(loop_device).file_name()?;
+    let backing_file =
+        fs::read_to_string(Path::new("/sys/block").join(name).join("loop/backing_file")).ok()?;
+
+    // an image removed while attached is still reported, marked as such
+    let backing_file = backing_file.trim_end();
+    Some(PathBuf::from(
+        backing_file
+            .strip_suffix(" (deleted)")
+            .unwrap_or(backing_file),
+    ))
+}
+
+// Stop tracking the loop devices of the scratch disks, for them to be
+// released along with a vm outliving the session rather than detached
+// when the session ends
+pub fn untrack_loop_devices(disks: &[ScratchDisk]) {
+    LOOP_DEVICES
+        .lock()
+        .unwrap()
+        .retain(|dev| !disks.iter().any(|disk| disk.loop_device == *dev));
+}
+
+// Detach all the loop devices set up during the session and not released
+// along with their vm, so that repeated runs do not exhaust the loop
+// device pool. Failures are only logged.
+pub fn detach_loop_devices() {
+    let devices: Vec<String> = LOOP_DEVICES.lock().unwrap().drain(..).collect();
+
+    for device in devices {
+        match run_host_command("losetup", &["--detach", &device]) {
+            Ok(_) => info!(sl!(), "detached loop device {}", device),
+            Err(e) => warn!(sl!(), "failed to detach loop device {}: {:?}", device, e),
+        }
+    }
+}
+
+// Check the tap device name fits in a network interface name
+pub fn validate_tap_name(name: &str) -> Result<()> {
+    if name.len() > MAX_IFACE_NAME_LEN || name.contains('/') || name.contains(char::is_whitespace) {
+        return Err(anyhow!("invalid tap device name {:?}", name));
+    }
+
+    Ok(())
+}
+
+// Create the tap device and bring it up unless it exists already. Returns
+// whether it got created, an existing device being left for its owner to
+// remove.
+pub fn create_tap_device(name: &str, multi_queue: bool) -> Result<bool> {
+    if Path::new(SYS_CLASS_NET).join(name).exists() {
+        info!(sl!(), "using existing tap device {}", name);
+        return Ok(false);
+    }
+
+    let mut args = vec!["tuntap", "add", "dev", name, "mode", "tap"];
+    if multi_queue {
+        args.push("multi_queue");
+    }
+    run_host_command("ip", &args)?;
+
+    if let Err(e) = run_host_command("ip", &["link", "set", "dev", name, "up"]) {
+        let _ = delete_tap_device(name);
+        return Err(e);
+    }
+
+    info!(sl!(), "created tap device {}", name);
+    Ok(true)
+}
+
+// Remove a tap device created for a test vm, if any
+pub fn delete_tap_device(name: &str) -> Result<()> {
+    if name.is_empty() || !Path::new(SYS_CLASS_NET).join(name).exists() {
+        return Ok(());
+    }
+
+    run_host_command("ip", &["link", "delete", "dev", name])?;
+    Ok(())
+}
+
+// Random locally administered unicast MAC address for the guest network
+// interface
+pub fn random_mac_address() -> [u8; 6] {
+    let mut rng = rand::rng();
+    let mut mac = [0u8; 6];
+
+    for byte in mac.iter_mut() {
+        *byte = rng.random_range(0..=u8::MAX);
+    }
+    mac[0] = (mac[0] & 0xfe) | 0x02;
+
+    mac
+}
+
+// Parse a PCI address in the [DDDD:]BB:DD.F form, returning it with the
+// domain, which defaults to 0000
+pub fn parse_pci_bdf(bdf: &str) -> Result<String> {
+    let err = || anyhow!("invalid PCI address {:?}: expected [DDDD:]BB:DD.F", bdf);
+
+    let (rest, function) = bdf.rsplit_once('.').ok_or_else(err)?;
+    let fields: Vec<&str> = rest.split(':').collect();
+    let (domain, bus, device) = match fields.as_slice() {
+        [bus, device] => ("0000", *bus, *device),
+        [domain, bus, device] => (*domain, *bus, *device),
+        _ => return Err(err()),
+    };
+
+    let is_hex = |field: &str, len: usize| {
+        field.len() == len && field.chars().all(|c| c.is_ascii_hexdigit())
+    };
+    if !is_hex(domain, 4) || !is_hex(bus, 2) || !is_hex(device, 2) || !is_hex(function, 1) {
+        return Err(err());
+    }
+
+    // 32 devices of 8 functions per bus
+    if u8::from_str_radix(device, 16)? > 0x1f || u8::from_str_radix(function, 16)? > 7 {
+        return Err(err());
+    }
+
+    Ok(format!("{domain}:{bus}:{device}.{function}").to_lowercase())
+}
+
+// Check the PCI address is well formed and names a host device bound to
+// vfio-pci
+pub fn validate_vfio_device(bdf: &str) -> Result<()> {
+    let dbdf = parse_pci_bdf(bdf)?;
+    let device_path = Path::new(SYS_BUS_PCI_DEVICES).join(&dbdf);
+    if !device_path.exists() {
+        return Err(anyhow!("no host PCI device at {}", dbdf));
+    }
+
+    let driver = fs::read_link(device_path.join("driver")).ok();
+    let driver = driver
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if driver != VFIO_PCI_DRIVER {
+        return Err(anyhow!(
+            "PCI device {} is bound to {:?} instead of {}",
+            dbdf,
+            driver,
+            VFIO_PCI_DRIVER
+        ));
+    }
+
+    Ok(())
+}
+
+// Check the host can back the guest memory of MEMORY_MB megabytes with
+// hugepages of the type: enough free hugetlbfs pages, or transparent
+// hugepages not disabled
+pub fn validate_hugepages(hugepage_type: &HugePageType, memory_mb: u32) -> Result<()> {
+    match hugepage_type {
+        HugePageType::Hugetlbfs => {
+            let meminfo = fs::read_to_string(PROC_MEMINFO)
+                .with_context(|| format!("read {}", PROC_MEMINFO))?;
+            let free_mb = free_hugepages_mb(&meminfo).unwrap_or(0);
+
+            if free_mb < u64::from(memory_mb) {
+                return Err(anyhow!(
+                    "not enough free hugepages on the host: {} MiB free, {} MiB needed (see /proc/sys/vm/nr_hugepages)",
+                    free_mb,
+                    memory_mb
+                ));
+            }
+        }
+        HugePageType::THP => {
+            let enabled = fs::read_to_string(SYS_THP_ENABLED)
+                .with_context(|| format!("read {}", SYS_THP_ENABLED))?;
+
+            if enabled.contains("[never]") {
+                return Err(anyhow!(
+                    "transparent hugepages are disabled on the host ({})",
+                    SYS_THP_ENABLED
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Size in megabytes of the free hugetlbfs pages, from the pool count and
+// page size in kilobytes of the meminfo
+fn free_hugepages_mb(meminfo: &str) -> Option<u64> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+
+    let free = field("HugePages_Free:")?;
+    let page_kb = field("Hugepagesize:")?;
+
+    Some(free * page_kb / 1024)
+}
+
+// Returns true once the process is gone or only left as a zombie, whose
+// resources were released already
+pub fn process_exited(pid: u32) -> bool {
+    match fs::read_to_string(format!("/proc/{pid}/stat")) {
+        // the state follows the command name, which is in parentheses
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+        Err(_) => true,
+    }
+}
+
+// Start time of the process, in clock ticks since boot, None if it is gone
+pub fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    parse_start_time(&stat)
+}
+
+// Get the start time, the 22nd field, out of a /proc/PID/stat line. The
+// fields are counted from the state, the 3rd one, as the command name in
+// parentheses may contain spaces.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+// Check that the directory looks like a bootable rootfs
+pub fn validate_rootfs_dir(dir: &str) -> Result<()> {
+    if !Path::new(dir).is_dir() {
+        return Err(anyhow!("rootfs {} is not a directory", dir));
+    }
+
+    // init is commonly a symlink, which only resolves inside the guest
+    let has_init = ROOTFS_INIT_PATHS
+        .iter()
+        .any(|init| fs::symlink_metadata(Path::new(dir).join(init)).is_ok());
+
+    if !has_init {
+        return Err(anyhow!(
+            "rootfs {} has no init (looked for {:?})",
+            dir,
+            ROOTFS_INIT_PATHS
+        ));
+    }
+
+    Ok(())
+}
+
+// Unmount all the filesystems mounted below the base path, deepest first,
+// so that the base path can be safely removed.
+pub fn umount_all_under(base: &str) -> Result<()> {
+    let mountinfo = fs::read_to_string(PROC_MOUNTINFO).context("read mountinfo")?;
+    let base = Path::new(base);
+
+    let mut mount_points: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .map(PathBuf::from)
+        .filter(|p| p.starts_with(base) && p != base)
+        .collect();
+
+    mount_points.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+    mount_points.dedup();
+
+    for mount_point in mount_points {
+        info!(sl!(), "unmounting {:?}", mount_point);
+        mount::umount_all(&mount_point, true)
+            .with_context(|| format!("failed to umount {mount_point:?}"))?;
+    }
+
+    Ok(())
+}
+
 fn update_agent_kernel_params(config: &mut TomlConfig) -> Result<()> {
     let mut params = vec![];
     if let Ok(kv) = config.get_agent_kernel_params() {
         for (k, v) in kv.into_iter() {
-            if let Ok(s) = to_kernel_string(k.to_owned(), v.to_owned()) {
-                params.push(s);
+            match to_kernel_string(k.to_owned(), v.to_owned()) {
+                Ok(s) => params.push(s),
+                Err(e) => warn!(sl!(), "skipping agent kernel param: {}", e),
             }
         }
         if let Some(h) = config.hypervisor.get_mut(&config.runtime.hypervisor_name) {
-            h.boot_info.add_kernel_params(params);
+            // the configured parameters come last, taking precedence
+            params.extend(
+                h.boot_info
+                    .kernel_params
+                    .split_whitespace()
+                    .map(|p| p.to_string()),
+            );
+            h.boot_info.kernel_params = merge_kernel_params(&params).join(" ");
         }
     }
     Ok(())
 }
 
+// Merge the kernel parameters by key, a later value overriding an earlier
+// one at the position of the latter, and bare flags being kept once
+fn merge_kernel_params(params: &[String]) -> Vec<String> {
+    let key = |param: &str| param.split('=').next().unwrap_or_default().to_string();
+    let mut merged: Vec<String> = Vec::new();
+
+    for param in params {
+        match merged.iter_mut().find(|p| key(p) == key(param)) {
+            Some(existing) => *existing = param.clone(),
+            None => merged.push(param.clone()),
+        }
+    }
+
+    merged
+}
+
 // Create the container rootfs host share path
 fn get_host_share_path(host_path: &str, id: &str) -> String {
     format!("{host_path}/{id}/{ROOTFS}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use test_utils::skip_if_not_root;
+
+    #[test]
+    fn test_to_kernel_string() {
+        #[derive(Debug)]
+        struct TestData<'a> {
+            key: &'a str,
+            val: &'a str,
+            result: Result<&'a str, &'a str>,
+        }
+
+        let tests = &[
+            TestData {
+                key: "",
+                val: "",
+                result: Err("Empty key and value"),
+            },
+            TestData {
+                key: "",
+                val: "val",
+                result: Err("Empty key"),
+            },
+            TestData {
+                key: "quiet",
+                val: "",
+                result: Ok("quiet"),
+            },
+            TestData {
+                key: "key",
+                val: "val",
+                result: Ok("key=val"),
+            },
+            TestData {
+                key: "root",
+                val: "UUID=1234",
+                result: Ok("root=UUID=1234"),
+            },
+            TestData {
+                key: "agent.log",
+                val: "debug",
+                result: Ok("agent.log=debug"),
+            },
+            TestData {
+                key: "bad key",
+                val: "val",
+                result: Err("Invalid key \"bad key\": must not contain whitespace or '='"),
+            },
+            TestData {
+                key: "\tkey",
+                val: "",
+                result: Err("Invalid key \"\\tkey\": must not contain whitespace or '='"),
+            },
+            TestData {
+                key: "a=b",
+                val: "c",
+                result: Err("Invalid key \"a=b\": must not contain whitespace or '='"),
+            },
+            TestData {
+                key: "key",
+                val: "two words",
+                result: Err("Invalid value \"two words\" for key key: must not contain whitespace"),
+            },
+        ];
+
+        for (i, d) in tests.iter().enumerate() {
+            let msg = format!("test[{}]: {:?}", i, d);
+
+            let result = to_kernel_string(d.key.to_string(), d.val.to_string());
+
+            match d.result {
+                Ok(expected) => assert_eq!(result.unwrap(), expected, "{}", msg),
+                Err(expected) => {
+                    assert_eq!(result.unwrap_err().to_string(), expected, "{}", msg)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_start_time() {
+        let tests: &[(&str, Option<u64>)] = &[
+            (
+                "1234 (qemu-system-x86) S 1 1234 1234 0 -1 4194560 100 0 0 0 5 3 0 0 20 0 4 0 98765 1000 200",
+                Some(98765),
+            ),
+            // spaces and parentheses in the command name
+            (
+                "42 (a (b) c) R 1 42 42 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 777 0 0",
+                Some(777),
+            ),
+            // truncated
+            ("42 (virtiofsd) S 1 42 42", None),
+            ("", None),
+        ];
+
+        for (i, (stat, expected)) in tests.iter().enumerate() {
+            assert_eq!(parse_start_time(stat), *expected, "test[{}]: {:?}", i, stat);
+        }
+    }
+
+    #[test]
+    fn test_merge_kernel_params() {
+        let tests: &[(&[&str], &[&str])] = &[
+            (&[], &[]),
+            (&["a=1", "b=2"], &["a=1", "b=2"]),
+            // later values override earlier ones
+            (&["a=1", "b=2", "a=3"], &["a=3", "b=2"]),
+            // bare flags are kept once
+            (&["quiet", "a=1", "quiet"], &["quiet", "a=1"]),
+            // a flag and a value for the same key
+            (&["debug", "debug=1"], &["debug=1"]),
+            // only the first '=' separates the key
+            (
+                &["init=/sbin/init", "root=UUID=1", "root=UUID=2"],
+                &["init=/sbin/init", "root=UUID=2"],
+            ),
+        ];
+
+        for (i, (params, expected)) in tests.iter().enumerate() {
+            let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+            let merged = merge_kernel_params(&params);
+            assert_eq!(merged, *expected, "test[{}]: {:?}", i, params);
+        }
+    }
+
+    #[test]
+    fn test_free_hugepages_mb() {
+        #[derive(Debug)]
+        struct TestData<'a> {
+            meminfo: &'a str,
+            result: Option<u64>,
+        }
+
+        let tests = &[
+            TestData {
+                meminfo: "HugePages_Total:     512\nHugePages_Free:      256\nHugepagesize:       2048 kB\n",
+                result: Some(512),
+            },
+            TestData {
+                meminfo: "HugePages_Total:       0\nHugePages_Free:        0\nHugepagesize:       2048 kB\n",
+                result: Some(0),
+            },
+            TestData {
+                meminfo: "HugePages_Free:        2\nHugepagesize:    1048576 kB\n",
+                result: Some(2048),
+            },
+            TestData {
+                meminfo: "MemTotal:       16384000 kB\n",
+                result: None,
+            },
+            TestData {
+                meminfo: "HugePages_Free:        x\nHugepagesize:       2048 kB\n",
+                result: None,
+            },
+        ];
+
+        for (i, d) in tests.iter().enumerate() {
+            let msg = format!("test[{}]: {:?}", i, d);
+
+            assert_eq!(free_hugepages_mb(d.meminfo), d.result, "{}", msg);
+        }
+    }
+
+    #[test]
+    fn test_share_rootfs_readonly() {
+        skip_if_not_root!();
+
+        let bundle = tempdir().unwrap();
+        let rootfs = bundle.path().join(ROOTFS);
+        fs::create_dir(&rootfs).unwrap();
+
+        let shared = tempdir().unwrap();
+        let host_path = shared.path().to_str().unwrap();
+
+        let guest_path =
+            share_rootfs(bundle.path().to_str().unwrap(), host_path, "c1", true).unwrap();
+        assert_eq!(
+            guest_path,
+            format!("{DEFAULT_GUEST_BASE_PATH}/{GUEST_SHARED_PATH}/c1")
+        );
+
+        // the guest sees the share through the host path
+        let shared_file = Path::new(&get_host_share_path(host_path, "c1")).join("file");
+        let result = fs::write(&shared_file, "data");
+
+        unshare_rootfs(host_path, "c1").unwrap();
+
+        let err = result.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS), "{err:?}");
+
+        // the source stays writable
+        fs::write(rootfs.join("file"), "data").unwrap();
+    }
+}